@@ -0,0 +1,133 @@
+//! The per-thread IPC buffer.
+//!
+//! Messages that don't fit in the message registers are passed through a
+//! page registered with the kernel by each thread. On every IPC operation, the
+//! kernel copies the valid words in the sender's buffer into the receiver's.
+//!
+//! The buffer also holds the capabilities to transfer with the message. The
+//! kernel copies or moves each one from the sender's capability table into
+//! the receiver's before handing the descriptors over to the receiver.
+
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+use crate::raw::{CapId, MESSAGE_REGISTERS};
+
+/// Number of message words that fit in the IPC buffer.
+pub const IPC_BUFFER_WORDS: usize = 448;
+/// Maximum number of capabilities transferred with a single message.
+pub const MAX_CAP_TRANSFERS: usize = 16;
+
+/// How a capability is transferred to the receiver.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
+#[repr(u32)]
+pub enum TransferMode {
+    /// The sender keeps its capability.
+    Copy = 0,
+    /// The sender's slot is emptied.
+    Move = 1,
+}
+
+/// Describes a capability to transfer with the message.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CapTransfer {
+    /// The capability in the sender's table.
+    pub source: CapId,
+    /// The empty slot in the receiver's table.
+    pub destination: CapId,
+    mode: u32,
+    _reserved: u32,
+}
+
+impl CapTransfer {
+    pub const fn new(source: CapId, destination: CapId, mode: TransferMode) -> Self {
+        Self {
+            source,
+            destination,
+            mode: mode as u32,
+            _reserved: 0,
+        }
+    }
+
+    /// Returns the transfer mode, if valid.
+    pub fn mode(&self) -> Option<TransferMode> {
+        TransferMode::try_from(self.mode).ok()
+    }
+}
+
+/// Layout of the page registered as a thread's IPC buffer.
+#[repr(C, align(4096))]
+#[derive(Debug, Clone)]
+pub struct IpcBuffer {
+    /// Number of valid words in `words`.
+    ///
+    /// The sender must set it on every operation, to 0 if the message fits in
+    /// the message registers.
+    pub length: usize,
+    pub words: [usize; IPC_BUFFER_WORDS],
+    /// Number of valid descriptors in `transfers`.
+    ///
+    /// Like `length`, the sender must set it on every operation.
+    pub transfer_count: usize,
+    pub transfers: [CapTransfer; MAX_CAP_TRANSFERS],
+}
+
+const _: () = assert!(core::mem::size_of::<IpcBuffer>() == 4096);
+const _: () = assert!(IPC_BUFFER_WORDS > MESSAGE_REGISTERS);
+
+impl IpcBuffer {
+    pub const fn new() -> Self {
+        Self {
+            length: 0,
+            words: [0; IPC_BUFFER_WORDS],
+            transfer_count: 0,
+            transfers: [CapTransfer::new(CapId::new(0), CapId::new(0), TransferMode::Copy);
+                MAX_CAP_TRANSFERS],
+        }
+    }
+
+    /// Returns the valid words in the buffer.
+    pub fn message(&self) -> &[usize] {
+        &self.words[..self.length.min(IPC_BUFFER_WORDS)]
+    }
+
+    /// Returns the valid capability transfer descriptors in the buffer.
+    pub fn cap_transfers(&self) -> &[CapTransfer] {
+        &self.transfers[..self.transfer_count.min(MAX_CAP_TRANSFERS)]
+    }
+}
+
+impl Default for IpcBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::boxed::Box;
+
+    use super::*;
+
+    #[test]
+    fn message_is_bounded() {
+        let mut buffer = Box::new(IpcBuffer::new());
+        assert!(buffer.message().is_empty());
+        buffer.words[..3].copy_from_slice(&[1, 2, 3]);
+        buffer.length = 3;
+        assert_eq!(buffer.message(), &[1, 2, 3]);
+        buffer.length = usize::MAX;
+        assert_eq!(buffer.message().len(), IPC_BUFFER_WORDS);
+    }
+
+    #[test]
+    fn transfer_mode() {
+        let transfer = CapTransfer::new(CapId::new(1), CapId::new(2), TransferMode::Move);
+        assert_eq!(transfer.mode(), Some(TransferMode::Move));
+        let mut raw = transfer;
+        raw.mode = 7;
+        assert_eq!(raw.mode(), None);
+    }
+}
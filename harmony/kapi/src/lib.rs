@@ -2,5 +2,7 @@
 #![no_std]
 #![feature(naked_functions)]
 
+pub mod ipc;
 pub mod ops;
 pub mod raw;
+pub mod time;
@@ -30,6 +30,11 @@ pub mod thread {
     pub enum ThreadOp {
         Activate,
         ChangeAffinity,
+        /// Registers the page at `address` in the thread's address space as
+        /// its IPC buffer. An address of 0 unregisters the buffer.
+        SetIpcBuffer {
+            address: usize,
+        },
     }
 
     impl SyscallOp for ThreadOp {
@@ -43,6 +48,9 @@ pub mod thread {
                 ThreadOp::ChangeAffinity => {
                     todo!();
                 }
+                ThreadOp::SetIpcBuffer { address } => {
+                    SyscallArgs::new(RawOperation::ThreadSetIpcBuffer.into(), address, 0, 0, 0)
+                }
             }
         }
 
@@ -51,6 +59,9 @@ pub mod thread {
             match op {
                 RawOperation::ThreadActivate => Ok(Self::Activate),
                 RawOperation::ThreadChangeAffinity => Ok(Self::ChangeAffinity),
+                RawOperation::ThreadSetIpcBuffer => Ok(Self::SetIpcBuffer {
+                    address: args.args().0,
+                }),
                 _ => Err(InvalidOperation::BadOp),
             }
         }
@@ -65,7 +76,17 @@ pub mod cap_table {
     use super::{InvalidOperation, SyscallOp};
     use crate::raw::{CapId, RawOperation, SyscallArgs};
 
-    #[derive(Debug, Copy, Clone)]
+    /// Slot of the boot component's own capability table.
+    pub const BOOT_CAP_TABLE_CAP: CapId = CapId::new(0);
+
+    /// Bits of the slot word holding the slot, the rest holds the stack
+    /// pointer of a constructed thread.
+    const SLOT_BITS: usize = 16;
+    /// User addresses are below the canonical hole, which leaves room for the
+    /// kind and the slot in their words.
+    const ADDRESS_LIMIT: usize = 1 << 47;
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     #[repr(C)]
     pub enum ConstructArgs {
         CapTable,
@@ -78,6 +99,94 @@ pub mod cap_table {
         PageTable {
             level: u8,
         },
+        Endpoint,
+        Notification,
+        /// Invocations start at `entry` on the stack at `stack_pointer`.
+        Component {
+            entry: usize,
+            stack_pointer: usize,
+            cap_table: CapId,
+            page_table: CapId,
+        },
+    }
+
+    impl ConstructArgs {
+        const CAP_TABLE: usize = 0;
+        const THREAD: usize = 1;
+        const PAGE_TABLE: usize = 2;
+        const ENDPOINT: usize = 3;
+        const NOTIFICATION: usize = 4;
+        const COMPONENT: usize = 5;
+        /// Sent for arguments that can't be encoded, which the kernel rejects.
+        const INVALID: usize = 0xFF;
+
+        /// Packs the kind and its kind-specific arguments into two words, and
+        /// a stack pointer.
+        fn into_words(self) -> (usize, usize, usize) {
+            match self {
+                ConstructArgs::CapTable => (Self::CAP_TABLE, 0, 0),
+                ConstructArgs::Thread {
+                    entry,
+                    stack_pointer,
+                    cap_table,
+                    page_table,
+                } => {
+                    if entry >= ADDRESS_LIMIT || stack_pointer >= ADDRESS_LIMIT {
+                        return (Self::INVALID, 0, 0);
+                    }
+                    (
+                        Self::THREAD | (entry << 8),
+                        usize::from(cap_table) | (usize::from(page_table) << 32),
+                        stack_pointer,
+                    )
+                }
+                ConstructArgs::PageTable { level } => {
+                    (Self::PAGE_TABLE | (usize::from(level) << 8), 0, 0)
+                }
+                ConstructArgs::Endpoint => (Self::ENDPOINT, 0, 0),
+                ConstructArgs::Notification => (Self::NOTIFICATION, 0, 0),
+                ConstructArgs::Component {
+                    entry,
+                    stack_pointer,
+                    cap_table,
+                    page_table,
+                } => {
+                    if entry >= ADDRESS_LIMIT || stack_pointer >= ADDRESS_LIMIT {
+                        return (Self::INVALID, 0, 0);
+                    }
+                    (
+                        Self::COMPONENT | (entry << 8),
+                        usize::from(cap_table) | (usize::from(page_table) << 32),
+                        stack_pointer,
+                    )
+                }
+            }
+        }
+
+        fn from_words(word: usize, extra: usize, stack: usize) -> Result<Self, InvalidOperation> {
+            match word & 0xFF {
+                Self::CAP_TABLE => Ok(ConstructArgs::CapTable),
+                Self::THREAD => Ok(ConstructArgs::Thread {
+                    entry: word >> 8,
+                    stack_pointer: stack,
+                    cap_table: CapId::new(extra as u32),
+                    page_table: CapId::new((extra >> 32) as u32),
+                }),
+                Self::PAGE_TABLE => Ok(ConstructArgs::PageTable {
+                    level: u8::try_from(word >> 8)
+                        .map_err(|_| InvalidOperation::InvalidArgument)?,
+                }),
+                Self::ENDPOINT => Ok(ConstructArgs::Endpoint),
+                Self::NOTIFICATION => Ok(ConstructArgs::Notification),
+                Self::COMPONENT => Ok(ConstructArgs::Component {
+                    entry: word >> 8,
+                    stack_pointer: stack,
+                    cap_table: CapId::new(extra as u32),
+                    page_table: CapId::new((extra >> 32) as u32),
+                }),
+                _ => Err(InvalidOperation::InvalidArgument),
+            }
+        }
     }
 
     #[derive(Debug, Copy, Clone)]
@@ -89,9 +198,12 @@ pub mod cap_table {
         Unlink {
             slot: SlotId<SLOT_COUNT>,
         },
+        /// Constructs a kernel object in the untyped frame at `offset` (in
+        /// frames) within the memory region.
         Construct {
             kind: ConstructArgs,
-            region: usize,
+            region: CapId,
+            offset: u32,
             slot: SlotId<SLOT_COUNT>,
         },
         Drop {
@@ -123,13 +235,23 @@ pub mod cap_table {
                     SyscallArgs::new(RawOperation::CapTableUnlink.into(), slot.into(), 0, 0, 0)
                 }
                 CapTableOp::Construct {
-                    kind: _,
-                    slot: _,
-                    region: _,
+                    kind,
+                    region,
+                    offset,
+                    slot,
                 } => {
-                    todo!()
+                    let (kind, extra, stack) = kind.into_words();
+                    SyscallArgs::new(
+                        RawOperation::CapTableConstruct.into(),
+                        kind,
+                        usize::from(region) | (offset as usize) << 32,
+                        usize::from(slot) | stack << SLOT_BITS,
+                        extra,
+                    )
+                }
+                CapTableOp::Drop { slot } => {
+                    SyscallArgs::new(RawOperation::CapTableDrop.into(), slot.into(), 0, 0, 0)
                 }
-                CapTableOp::Drop { slot: _ } => todo!(),
                 CapTableOp::Copy {
                     slot: _,
                     other_table_cap: _,
@@ -162,8 +284,29 @@ pub mod cap_table {
                         .map_err(|_| InvalidOperation::InvalidArgument)?;
                     Ok(Self::Unlink { slot })
                 }
-                RawOperation::CapTableConstruct => todo!(),
-                RawOperation::CapTableDrop => todo!(),
+                RawOperation::CapTableConstruct => {
+                    let (word, _, slot, extra) = args.args();
+                    let kind = ConstructArgs::from_words(word, extra, slot >> SLOT_BITS)?;
+                    let region = CapId::new(args.args().1 as u32);
+                    let offset = (args.args().1 >> 32) as u32;
+                    let slot = (slot & ((1 << SLOT_BITS) - 1))
+                        .try_into()
+                        .map_err(|_| InvalidOperation::InvalidArgument)?;
+                    Ok(Self::Construct {
+                        kind,
+                        region,
+                        offset,
+                        slot,
+                    })
+                }
+                RawOperation::CapTableDrop => {
+                    let slot = args
+                        .args()
+                        .0
+                        .try_into()
+                        .map_err(|_| InvalidOperation::InvalidArgument)?;
+                    Ok(Self::Drop { slot })
+                }
                 RawOperation::CapTableCopy => todo!(),
                 _ => Err(InvalidOperation::BadOp),
             }
@@ -172,3 +315,838 @@ pub mod cap_table {
         fn convert_success_code(&self, _code: usize) -> Self::R {}
     }
 }
+
+pub mod clock {
+    use super::{InvalidOperation, SyscallOp};
+    use crate::raw::{CapId, RawOperation, SyscallArgs};
+
+    /// Slot of the clock capability in the boot component's table.
+    pub const BOOT_CLOCK_CAP: CapId = CapId::new(2);
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum ClockOp {
+        /// Reads the monotonic clock in nanoseconds.
+        Monotonic,
+    }
+
+    impl SyscallOp for ClockOp {
+        type R = u64;
+
+        fn into_args(self) -> SyscallArgs {
+            match self {
+                ClockOp::Monotonic => {
+                    SyscallArgs::new(RawOperation::ClockMonotonic.into(), 0, 0, 0, 0)
+                }
+            }
+        }
+
+        fn from_args(args: SyscallArgs) -> Result<Self, InvalidOperation> {
+            let op = RawOperation::try_from(args.op()).map_err(|_| InvalidOperation::BadOp)?;
+            match op {
+                RawOperation::ClockMonotonic => Ok(Self::Monotonic),
+                _ => Err(InvalidOperation::BadOp),
+            }
+        }
+
+        fn convert_success_code(&self, code: usize) -> Self::R {
+            code as u64
+        }
+    }
+}
+
+pub mod endpoint {
+    use super::{InvalidOperation, SyscallOp};
+    use crate::raw::{ipc, CapError, CapId, RawOperation, SyscallArgs, MESSAGE_REGISTERS};
+
+    pub type Message = [usize; MESSAGE_REGISTERS];
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum EndpointOp {
+        /// Sends the message to the thread waiting on the endpoint and blocks
+        /// until it replies.
+        Call { message: Message },
+        /// Replies to the last caller.
+        Reply { message: Message },
+        /// Replies to the last caller and waits for the next call.
+        ReplyRecv { message: Message },
+    }
+
+    impl EndpointOp {
+        /// Performs the operation, returning the message received in response.
+        ///
+        /// # Safety
+        ///
+        /// Syscalls can fundamentally change memory
+        pub unsafe fn invoke(self, capability: CapId) -> Result<Message, CapError> {
+            unsafe { ipc(capability, self.into_args()) }
+        }
+    }
+
+    impl SyscallOp for EndpointOp {
+        type R = ();
+
+        fn into_args(self) -> SyscallArgs {
+            let (op, [a, b, c, d]) = match self {
+                EndpointOp::Call { message } => (RawOperation::EndpointCall, message),
+                EndpointOp::Reply { message } => (RawOperation::EndpointReply, message),
+                EndpointOp::ReplyRecv { message } => (RawOperation::EndpointReplyRecv, message),
+            };
+            SyscallArgs::new(op.into(), a, b, c, d)
+        }
+
+        fn from_args(args: SyscallArgs) -> Result<Self, InvalidOperation> {
+            let op = RawOperation::try_from(args.op()).map_err(|_| InvalidOperation::BadOp)?;
+            let (a, b, c, d) = args.args();
+            let message = [a, b, c, d];
+            match op {
+                RawOperation::EndpointCall => Ok(Self::Call { message }),
+                RawOperation::EndpointReply => Ok(Self::Reply { message }),
+                RawOperation::EndpointReplyRecv => Ok(Self::ReplyRecv { message }),
+                _ => Err(InvalidOperation::BadOp),
+            }
+        }
+
+        fn convert_success_code(&self, _code: usize) -> Self::R {}
+    }
+}
+
+pub mod notification {
+    use super::{InvalidOperation, SyscallOp};
+    use crate::raw::{RawOperation, SyscallArgs};
+
+    /// Mask of the usable bits in a notification word. The top bit is
+    /// reserved, as syscall results use it to mark errors.
+    pub const NOTIFICATION_MASK: u64 = (1 << 63) - 1;
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum NotificationOp {
+        /// Sets the bits on the notification, waking the waiting thread if any.
+        Signal { bits: u64 },
+        /// Consumes the pending bits, blocking until there are any.
+        Wait,
+        /// Consumes the pending bits without blocking.
+        Poll,
+    }
+
+    impl SyscallOp for NotificationOp {
+        type R = u64;
+
+        fn into_args(self) -> SyscallArgs {
+            match self {
+                NotificationOp::Signal { bits } => SyscallArgs::new(
+                    RawOperation::NotificationSignal.into(),
+                    bits as usize,
+                    0,
+                    0,
+                    0,
+                ),
+                NotificationOp::Wait => {
+                    SyscallArgs::new(RawOperation::NotificationWait.into(), 0, 0, 0, 0)
+                }
+                NotificationOp::Poll => {
+                    SyscallArgs::new(RawOperation::NotificationPoll.into(), 0, 0, 0, 0)
+                }
+            }
+        }
+
+        fn from_args(args: SyscallArgs) -> Result<Self, InvalidOperation> {
+            let op = RawOperation::try_from(args.op()).map_err(|_| InvalidOperation::BadOp)?;
+            match op {
+                RawOperation::NotificationSignal => {
+                    let bits = args.args().0 as u64;
+                    if bits & !NOTIFICATION_MASK != 0 {
+                        return Err(InvalidOperation::InvalidArgument);
+                    }
+                    Ok(Self::Signal { bits })
+                }
+                RawOperation::NotificationWait => Ok(Self::Wait),
+                RawOperation::NotificationPoll => Ok(Self::Poll),
+                _ => Err(InvalidOperation::BadOp),
+            }
+        }
+
+        fn convert_success_code(&self, code: usize) -> Self::R {
+            code as u64
+        }
+    }
+}
+
+pub mod component {
+    use super::{InvalidOperation, SyscallOp};
+    use crate::ops::endpoint::Message;
+    use crate::raw::{ipc, CapError, CapId, RawOperation, SyscallArgs};
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum ComponentOp {
+        /// Migrates the current thread into the component, starting at its
+        /// entry point with the message.
+        Invoke { message: Message },
+        /// Returns from the current invocation back into the invoking
+        /// component with the message.
+        ///
+        /// Doesn't require a capability, any capability id may be passed.
+        Return { message: Message },
+    }
+
+    impl ComponentOp {
+        /// Performs the operation, returning the message received in response.
+        ///
+        /// # Safety
+        ///
+        /// Syscalls can fundamentally change memory
+        pub unsafe fn invoke(self, capability: CapId) -> Result<Message, CapError> {
+            unsafe { ipc(capability, self.into_args()) }
+        }
+    }
+
+    impl SyscallOp for ComponentOp {
+        type R = ();
+
+        fn into_args(self) -> SyscallArgs {
+            let (op, [a, b, c, d]) = match self {
+                ComponentOp::Invoke { message } => (RawOperation::ComponentInvoke, message),
+                ComponentOp::Return { message } => (RawOperation::ComponentReturn, message),
+            };
+            SyscallArgs::new(op.into(), a, b, c, d)
+        }
+
+        fn from_args(args: SyscallArgs) -> Result<Self, InvalidOperation> {
+            let op = RawOperation::try_from(args.op()).map_err(|_| InvalidOperation::BadOp)?;
+            let (a, b, c, d) = args.args();
+            let message = [a, b, c, d];
+            match op {
+                RawOperation::ComponentInvoke => Ok(Self::Invoke { message }),
+                RawOperation::ComponentReturn => Ok(Self::Return { message }),
+                _ => Err(InvalidOperation::BadOp),
+            }
+        }
+
+        fn convert_success_code(&self, _code: usize) -> Self::R {}
+    }
+}
+
+pub mod page_table {
+    use super::{InvalidOperation, SyscallOp};
+    use crate::raw::{CapId, RawOperation, SyscallArgs};
+
+    /// Slot of the boot component's top-level page table.
+    pub const BOOT_PAGE_TABLE_CAP: CapId = CapId::new(1);
+
+    /// Access rights of a page table entry.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub struct PageFlags(usize);
+
+    impl PageFlags {
+        pub const READ_ONLY: Self = Self(0);
+        pub const WRITABLE: Self = Self(1 << 0);
+        pub const EXECUTABLE: Self = Self(1 << 1);
+        /// Maps device memory as write-combining instead of uncacheable.
+        ///
+        /// Only allowed when mapping from a device region.
+        pub const WRITE_COMBINING: Self = Self(1 << 2);
+        const ALL: usize = Self::WRITABLE.0 | Self::EXECUTABLE.0 | Self::WRITE_COMBINING.0;
+
+        pub const fn union(self, other: Self) -> Self {
+            Self(self.0 | other.0)
+        }
+
+        pub const fn contains(&self, other: Self) -> bool {
+            self.0 & other.0 == other.0
+        }
+
+        pub const fn bits(&self) -> usize {
+            self.0
+        }
+
+        pub const fn from_bits(bits: usize) -> Option<Self> {
+            if bits & !Self::ALL != 0 {
+                return None;
+            }
+            Some(Self(bits))
+        }
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum PageTableOp {
+        /// Links the page table one level below into the entry.
+        Link {
+            offset: u16,
+            table: CapId,
+            flags: PageFlags,
+        },
+        /// Maps the frames starting `frame` frames into the memory region into
+        /// the entry.
+        ///
+        /// The region can also be a device region, in which case the frames
+        /// must all be device memory and are mapped uncacheable (or
+        /// write-combining).
+        ///
+        /// Entries in L2 and L3 tables map 2MiB and 1GiB frames respectively.
+        Map {
+            offset: u16,
+            region: CapId,
+            frame: u32,
+            flags: PageFlags,
+        },
+        /// Clears the entry, unlinking the table or unmapping the frame.
+        Unlink { offset: u16 },
+        /// Changes the access rights of the entry.
+        ChangeFlags { offset: u16, flags: PageFlags },
+    }
+
+    impl SyscallOp for PageTableOp {
+        type R = ();
+
+        fn into_args(self) -> SyscallArgs {
+            match self {
+                PageTableOp::Link {
+                    offset,
+                    table,
+                    flags,
+                } => SyscallArgs::new(
+                    RawOperation::PageTableLink.into(),
+                    offset.into(),
+                    table.into(),
+                    flags.bits(),
+                    0,
+                ),
+                PageTableOp::Map {
+                    offset,
+                    region,
+                    frame,
+                    flags,
+                } => SyscallArgs::new(
+                    RawOperation::PageTableMap.into(),
+                    offset.into(),
+                    usize::from(region) | (frame as usize) << 32,
+                    flags.bits(),
+                    0,
+                ),
+                PageTableOp::Unlink { offset } => {
+                    SyscallArgs::new(RawOperation::PageTableUnlink.into(), offset.into(), 0, 0, 0)
+                }
+                PageTableOp::ChangeFlags { offset, flags } => SyscallArgs::new(
+                    RawOperation::PageTableChangeFlags.into(),
+                    offset.into(),
+                    flags.bits(),
+                    0,
+                    0,
+                ),
+            }
+        }
+
+        fn from_args(args: SyscallArgs) -> Result<Self, InvalidOperation> {
+            let op = RawOperation::try_from(args.op()).map_err(|_| InvalidOperation::BadOp)?;
+            let (a, b, c, _d) = args.args();
+            let offset = u16::try_from(a).map_err(|_| InvalidOperation::InvalidArgument)?;
+            let flags = |bits| PageFlags::from_bits(bits).ok_or(InvalidOperation::InvalidArgument);
+            match op {
+                RawOperation::PageTableLink => Ok(Self::Link {
+                    offset,
+                    table: CapId::try_from(b).map_err(|_| InvalidOperation::InvalidArgument)?,
+                    flags: flags(c)?,
+                }),
+                RawOperation::PageTableMap => Ok(Self::Map {
+                    offset,
+                    region: CapId::new(b as u32),
+                    frame: (b >> 32) as u32,
+                    flags: flags(c)?,
+                }),
+                RawOperation::PageTableUnlink => Ok(Self::Unlink { offset }),
+                RawOperation::PageTableChangeFlags => Ok(Self::ChangeFlags {
+                    offset,
+                    flags: flags(b)?,
+                }),
+                _ => Err(InvalidOperation::BadOp),
+            }
+        }
+
+        fn convert_success_code(&self, _code: usize) -> Self::R {}
+    }
+}
+
+pub mod memory_region {
+    use super::{InvalidOperation, SyscallOp};
+    use crate::raw::{CapId, RawOperation, SyscallArgs};
+
+    /// Slot of the region covering all of physical memory in the boot
+    /// component's table.
+    pub const BOOT_MEMORY_CAP: CapId = CapId::new(3);
+
+    /// State an untyped frame can be retyped into.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum FrameKind {
+        User = 0,
+        Kernel = 1,
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum MemoryRegionOp {
+        /// Retypes the untyped frame at `offset` (in frames) within the region.
+        Retype { offset: u32, kind: FrameKind },
+        /// Shrinks the region to its first `offset` frames and puts the rest
+        /// into a new capability at `slot`.
+        Split { offset: u32, slot: CapId },
+        /// Scrubs the unreferenced frame at `offset` (in frames) within the
+        /// region and retypes it back into untyped memory.
+        Untype { offset: u32 },
+    }
+
+    impl SyscallOp for MemoryRegionOp {
+        type R = ();
+
+        fn into_args(self) -> SyscallArgs {
+            match self {
+                MemoryRegionOp::Retype { offset, kind } => SyscallArgs::new(
+                    RawOperation::MemoryRegionRetype.into(),
+                    offset as usize,
+                    kind as usize,
+                    0,
+                    0,
+                ),
+                MemoryRegionOp::Split { offset, slot } => SyscallArgs::new(
+                    RawOperation::MemoryRegionSplit.into(),
+                    offset as usize,
+                    slot.into(),
+                    0,
+                    0,
+                ),
+                MemoryRegionOp::Untype { offset } => SyscallArgs::new(
+                    RawOperation::MemoryRegionUntype.into(),
+                    offset as usize,
+                    0,
+                    0,
+                    0,
+                ),
+            }
+        }
+
+        fn from_args(args: SyscallArgs) -> Result<Self, InvalidOperation> {
+            let op = RawOperation::try_from(args.op()).map_err(|_| InvalidOperation::BadOp)?;
+            let (a, b, _c, _d) = args.args();
+            let offset = u32::try_from(a).map_err(|_| InvalidOperation::InvalidArgument)?;
+            match op {
+                RawOperation::MemoryRegionRetype => {
+                    let kind = match b {
+                        0 => FrameKind::User,
+                        1 => FrameKind::Kernel,
+                        _ => return Err(InvalidOperation::InvalidArgument),
+                    };
+                    Ok(Self::Retype { offset, kind })
+                }
+                RawOperation::MemoryRegionSplit => Ok(Self::Split {
+                    offset,
+                    slot: CapId::try_from(b).map_err(|_| InvalidOperation::InvalidArgument)?,
+                }),
+                RawOperation::MemoryRegionUntype => Ok(Self::Untype { offset }),
+                _ => Err(InvalidOperation::BadOp),
+            }
+        }
+
+        fn convert_success_code(&self, _code: usize) -> Self::R {}
+    }
+}
+
+pub mod device_region {
+    use super::{InvalidOperation, SyscallOp};
+    use crate::raw::{CapId, RawOperation, SyscallArgs};
+
+    /// Slot of the region covering all of the physical address space in the
+    /// boot component's table. Only its device memory can be mapped.
+    pub const BOOT_DEVICE_CAP: CapId = CapId::new(4);
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum DeviceRegionOp {
+        /// Shrinks the region to its first `offset` frames and puts the rest
+        /// into a new capability at `slot`.
+        Split { offset: u32, slot: CapId },
+    }
+
+    impl SyscallOp for DeviceRegionOp {
+        type R = ();
+
+        fn into_args(self) -> SyscallArgs {
+            match self {
+                DeviceRegionOp::Split { offset, slot } => SyscallArgs::new(
+                    RawOperation::DeviceRegionSplit.into(),
+                    offset as usize,
+                    slot.into(),
+                    0,
+                    0,
+                ),
+            }
+        }
+
+        fn from_args(args: SyscallArgs) -> Result<Self, InvalidOperation> {
+            let op = RawOperation::try_from(args.op()).map_err(|_| InvalidOperation::BadOp)?;
+            let (a, b, _c, _d) = args.args();
+            match op {
+                RawOperation::DeviceRegionSplit => Ok(Self::Split {
+                    offset: u32::try_from(a).map_err(|_| InvalidOperation::InvalidArgument)?,
+                    slot: CapId::try_from(b).map_err(|_| InvalidOperation::InvalidArgument)?,
+                }),
+                _ => Err(InvalidOperation::BadOp),
+            }
+        }
+
+        fn convert_success_code(&self, _code: usize) -> Self::R {}
+    }
+}
+
+pub mod io_ports {
+    use super::{InvalidOperation, SyscallOp};
+    use crate::raw::{CapId, RawOperation, SyscallArgs};
+
+    /// Slot of the range covering every I/O port in the boot component's
+    /// table. The ports driven by the kernel are never accessible.
+    pub const BOOT_IO_PORTS_CAP: CapId = CapId::new(5);
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum IoPortsOp {
+        /// Shrinks the range to its first `offset` ports and puts the rest
+        /// into a new capability at `slot`.
+        Split { offset: u32, slot: CapId },
+        /// Lets the thread at `thread` use `in` and `out` on the ports.
+        Grant { thread: CapId },
+        /// Takes the ports back from the thread at `thread`.
+        Revoke { thread: CapId },
+    }
+
+    impl SyscallOp for IoPortsOp {
+        type R = ();
+
+        fn into_args(self) -> SyscallArgs {
+            match self {
+                IoPortsOp::Split { offset, slot } => SyscallArgs::new(
+                    RawOperation::IoPortsSplit.into(),
+                    offset as usize,
+                    slot.into(),
+                    0,
+                    0,
+                ),
+                IoPortsOp::Grant { thread } => {
+                    SyscallArgs::new(RawOperation::IoPortsGrant.into(), thread.into(), 0, 0, 0)
+                }
+                IoPortsOp::Revoke { thread } => {
+                    SyscallArgs::new(RawOperation::IoPortsRevoke.into(), thread.into(), 0, 0, 0)
+                }
+            }
+        }
+
+        fn from_args(args: SyscallArgs) -> Result<Self, InvalidOperation> {
+            let op = RawOperation::try_from(args.op()).map_err(|_| InvalidOperation::BadOp)?;
+            let (a, b, _c, _d) = args.args();
+            let cap = |value: usize| {
+                CapId::try_from(value).map_err(|_| InvalidOperation::InvalidArgument)
+            };
+            match op {
+                RawOperation::IoPortsSplit => Ok(Self::Split {
+                    offset: u32::try_from(a).map_err(|_| InvalidOperation::InvalidArgument)?,
+                    slot: cap(b)?,
+                }),
+                RawOperation::IoPortsGrant => Ok(Self::Grant { thread: cap(a)? }),
+                RawOperation::IoPortsRevoke => Ok(Self::Revoke { thread: cap(a)? }),
+                _ => Err(InvalidOperation::BadOp),
+            }
+        }
+
+        fn convert_success_code(&self, _code: usize) -> Self::R {}
+    }
+}
+
+pub mod irq {
+    use super::notification::NOTIFICATION_MASK;
+    use super::{InvalidOperation, SyscallOp};
+    use crate::raw::{CapId, RawOperation, SyscallArgs};
+
+    /// Slot of the IRQ control capability in the boot component's table.
+    pub const BOOT_IRQ_CONTROL_CAP: CapId = CapId::new(6);
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum IrqControlOp {
+        /// Puts a handler capability for the IRQ `line` into `slot`. The lines
+        /// used by the kernel can't be handled.
+        Get { line: u8, slot: CapId },
+    }
+
+    impl SyscallOp for IrqControlOp {
+        type R = ();
+
+        fn into_args(self) -> SyscallArgs {
+            match self {
+                IrqControlOp::Get { line, slot } => SyscallArgs::new(
+                    RawOperation::IrqControlGet.into(),
+                    line as usize,
+                    slot.into(),
+                    0,
+                    0,
+                ),
+            }
+        }
+
+        fn from_args(args: SyscallArgs) -> Result<Self, InvalidOperation> {
+            let op = RawOperation::try_from(args.op()).map_err(|_| InvalidOperation::BadOp)?;
+            let (a, b, _c, _d) = args.args();
+            match op {
+                RawOperation::IrqControlGet => Ok(Self::Get {
+                    line: u8::try_from(a).map_err(|_| InvalidOperation::InvalidArgument)?,
+                    slot: CapId::try_from(b).map_err(|_| InvalidOperation::InvalidArgument)?,
+                }),
+                _ => Err(InvalidOperation::BadOp),
+            }
+        }
+
+        fn convert_success_code(&self, _code: usize) -> Self::R {}
+    }
+
+    /// Operations on an IRQ line.
+    ///
+    /// The line is masked whenever it fires, and stays masked until the
+    /// handler acknowledges it.
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum IrqHandlerOp {
+        /// Signals `bits` on the notification at `notification` whenever the
+        /// line fires, replacing the previous binding.
+        Bind { notification: CapId, bits: u64 },
+        /// Removes the notification binding.
+        Unbind,
+        /// Blocks the calling thread until the line fires, returning right
+        /// away if it fired since the last wait. Only allowed when the line
+        /// isn't bound to a notification.
+        Wait,
+        /// Unmasks the line once the device has been serviced.
+        Ack,
+    }
+
+    impl SyscallOp for IrqHandlerOp {
+        type R = ();
+
+        fn into_args(self) -> SyscallArgs {
+            match self {
+                IrqHandlerOp::Bind { notification, bits } => SyscallArgs::new(
+                    RawOperation::IrqHandlerBind.into(),
+                    notification.into(),
+                    bits as usize,
+                    0,
+                    0,
+                ),
+                IrqHandlerOp::Unbind => {
+                    SyscallArgs::new(RawOperation::IrqHandlerUnbind.into(), 0, 0, 0, 0)
+                }
+                IrqHandlerOp::Wait => {
+                    SyscallArgs::new(RawOperation::IrqHandlerWait.into(), 0, 0, 0, 0)
+                }
+                IrqHandlerOp::Ack => {
+                    SyscallArgs::new(RawOperation::IrqHandlerAck.into(), 0, 0, 0, 0)
+                }
+            }
+        }
+
+        fn from_args(args: SyscallArgs) -> Result<Self, InvalidOperation> {
+            let op = RawOperation::try_from(args.op()).map_err(|_| InvalidOperation::BadOp)?;
+            let (a, b, _c, _d) = args.args();
+            match op {
+                RawOperation::IrqHandlerBind => {
+                    let bits = b as u64;
+                    if bits == 0 || bits & !NOTIFICATION_MASK != 0 {
+                        return Err(InvalidOperation::InvalidArgument);
+                    }
+                    Ok(Self::Bind {
+                        notification: CapId::try_from(a)
+                            .map_err(|_| InvalidOperation::InvalidArgument)?,
+                        bits,
+                    })
+                }
+                RawOperation::IrqHandlerUnbind => Ok(Self::Unbind),
+                RawOperation::IrqHandlerWait => Ok(Self::Wait),
+                RawOperation::IrqHandlerAck => Ok(Self::Ack),
+                _ => Err(InvalidOperation::BadOp),
+            }
+        }
+
+        fn convert_success_code(&self, _code: usize) -> Self::R {}
+    }
+}
+
+pub mod pci {
+    use super::{InvalidOperation, SyscallOp};
+    use crate::raw::{CapId, RawOperation, SyscallArgs};
+
+    /// Slot of the PCI capability in the boot component's table.
+    pub const BOOT_PCI_CAP: CapId = CapId::new(7);
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum PciOp {
+        /// Puts a capability for the `index`-th function found at boot into
+        /// `slot`, returning its address packed as segment, bus, device and
+        /// function.
+        Function { index: u32, slot: CapId },
+    }
+
+    impl SyscallOp for PciOp {
+        type R = u32;
+
+        fn into_args(self) -> SyscallArgs {
+            match self {
+                PciOp::Function { index, slot } => SyscallArgs::new(
+                    RawOperation::PciFunction.into(),
+                    index as usize,
+                    slot.into(),
+                    0,
+                    0,
+                ),
+            }
+        }
+
+        fn from_args(args: SyscallArgs) -> Result<Self, InvalidOperation> {
+            let op = RawOperation::try_from(args.op()).map_err(|_| InvalidOperation::BadOp)?;
+            let (a, b, _c, _d) = args.args();
+            match op {
+                RawOperation::PciFunction => Ok(Self::Function {
+                    index: u32::try_from(a).map_err(|_| InvalidOperation::InvalidArgument)?,
+                    slot: CapId::try_from(b).map_err(|_| InvalidOperation::InvalidArgument)?,
+                }),
+                _ => Err(InvalidOperation::BadOp),
+            }
+        }
+
+        fn convert_success_code(&self, code: usize) -> Self::R {
+            code as u32
+        }
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    pub enum PciFunctionOp {
+        /// Reads the 4-byte aligned configuration register at `offset`.
+        Read { offset: u16 },
+        /// Writes the 4-byte aligned configuration register at `offset`. The
+        /// BARs and expansion ROM can't be written.
+        Write { offset: u16, value: u32 },
+        /// Puts a capability for the region decoded by the BAR at `index` into
+        /// `slot`, returning its size. Memory BARs yield a device region
+        /// covering the pages of the BAR, and I/O BARs a port range.
+        Bar { index: u8, slot: CapId },
+    }
+
+    impl SyscallOp for PciFunctionOp {
+        type R = usize;
+
+        fn into_args(self) -> SyscallArgs {
+            match self {
+                PciFunctionOp::Read { offset } => SyscallArgs::new(
+                    RawOperation::PciFunctionRead.into(),
+                    offset as usize,
+                    0,
+                    0,
+                    0,
+                ),
+                PciFunctionOp::Write { offset, value } => SyscallArgs::new(
+                    RawOperation::PciFunctionWrite.into(),
+                    offset as usize,
+                    value as usize,
+                    0,
+                    0,
+                ),
+                PciFunctionOp::Bar { index, slot } => SyscallArgs::new(
+                    RawOperation::PciFunctionBar.into(),
+                    index as usize,
+                    slot.into(),
+                    0,
+                    0,
+                ),
+            }
+        }
+
+        fn from_args(args: SyscallArgs) -> Result<Self, InvalidOperation> {
+            let op = RawOperation::try_from(args.op()).map_err(|_| InvalidOperation::BadOp)?;
+            let (a, b, _c, _d) = args.args();
+            let offset = || u16::try_from(a).map_err(|_| InvalidOperation::InvalidArgument);
+            match op {
+                RawOperation::PciFunctionRead => Ok(Self::Read { offset: offset()? }),
+                RawOperation::PciFunctionWrite => Ok(Self::Write {
+                    offset: offset()?,
+                    value: u32::try_from(b).map_err(|_| InvalidOperation::InvalidArgument)?,
+                }),
+                RawOperation::PciFunctionBar => Ok(Self::Bar {
+                    index: u8::try_from(a).map_err(|_| InvalidOperation::InvalidArgument)?,
+                    slot: CapId::try_from(b).map_err(|_| InvalidOperation::InvalidArgument)?,
+                }),
+                _ => Err(InvalidOperation::BadOp),
+            }
+        }
+
+        fn convert_success_code(&self, code: usize) -> Self::R {
+            code
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cap_table::{CapTableOp, ConstructArgs};
+    use super::{InvalidOperation, SyscallOp};
+    use crate::raw::CapId;
+
+    fn construct(kind: ConstructArgs) -> Result<ConstructArgs, InvalidOperation> {
+        let op = CapTableOp::<128>::Construct {
+            kind,
+            region: CapId::new(3),
+            offset: 7,
+            slot: 127.try_into().ok().unwrap(),
+        };
+        match CapTableOp::<128>::from_args(op.into_args())? {
+            CapTableOp::Construct {
+                kind,
+                region,
+                offset,
+                slot,
+            } => {
+                assert_eq!(region, CapId::new(3));
+                assert_eq!(offset, 7);
+                assert_eq!(usize::from(slot), 127);
+                Ok(kind)
+            }
+            _ => panic!("Decoded another operation"),
+        }
+    }
+
+    #[test]
+    fn thread_args_round_trip() {
+        let kind = ConstructArgs::Thread {
+            entry: 0x0000_7FFF_FFFF_F000,
+            stack_pointer: 0x0000_7FFF_FFFF_FFF8,
+            cap_table: CapId::new(u32::MAX),
+            page_table: CapId::new(1),
+        };
+        assert_eq!(construct(kind).ok(), Some(kind));
+    }
+
+    #[test]
+    fn kernel_addresses_are_rejected() {
+        let kind = ConstructArgs::Thread {
+            entry: 0xFFFF_8000_0000_0000,
+            stack_pointer: 0,
+            cap_table: CapId::new(0),
+            page_table: CapId::new(1),
+        };
+        assert!(construct(kind).is_err());
+        let kind = ConstructArgs::Component {
+            entry: 0x1000,
+            stack_pointer: 0xFFFF_FFFF_FFFF_FFF8,
+            cap_table: CapId::new(0),
+            page_table: CapId::new(1),
+        };
+        assert!(construct(kind).is_err());
+    }
+
+    #[test]
+    fn component_args_round_trip() {
+        let kind = ConstructArgs::Component {
+            entry: 0x0000_7FFF_FFFF_F000,
+            stack_pointer: 0x0000_7FFF_FFFF_FFF8,
+            cap_table: CapId::new(u32::MAX),
+            page_table: CapId::new(1),
+        };
+        assert_eq!(construct(kind).ok(), Some(kind));
+    }
+}
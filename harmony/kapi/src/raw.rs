@@ -3,6 +3,10 @@ use core::num::TryFromIntError;
 
 use num_enum::{IntoPrimitive, TryFromPrimitive, TryFromPrimitiveError};
 
+/// Capability index that prints the buffer in the next two arguments
+/// (pointer and length) to the kernel's debug output.
+pub const DEBUG_PRINT_CAP: usize = usize::MAX;
+
 /// Performs a raw syscall
 ///
 /// # Safety
@@ -38,6 +42,54 @@ pub unsafe extern "sysv64" fn raw_syscall(
     );
 }
 
+/// Performs a raw IPC syscall
+///
+/// Behaves like [`raw_syscall`] but also stores the message registers set by
+/// the kernel on return into `msg`.
+///
+/// # Safety
+///
+/// Performing a syscall is inherently unsafe, follow the syscall
+/// documentation to guarantee proper usage and soundness. `msg` must be valid
+/// for writes.
+#[naked]
+pub unsafe extern "sysv64" fn raw_ipc(
+    _a: usize,
+    _b: usize,
+    _c: usize,
+    _d: usize,
+    _e: usize,
+    _f: usize,
+    _msg: *mut [usize; MESSAGE_REGISTERS],
+) -> isize {
+    asm!(
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "int 0x80",
+        // The 7th argument is past the return address and the pushed registers.
+        "mov r10, [rsp + 56]",
+        "mov [r10], rdx",
+        "mov [r10 + 8], rcx",
+        "mov [r10 + 16], r8",
+        "mov [r10 + 24], r9",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "ret",
+        options(noreturn)
+    );
+}
+
+/// Number of words transferred in registers during IPC.
+pub const MESSAGE_REGISTERS: usize = 4;
+
 /// Performs a syscall
 ///
 /// # Safety
@@ -61,6 +113,31 @@ pub unsafe fn syscall(cap: CapId, args: SyscallArgs) -> Result<usize, CapError>
     }
 }
 
+/// Performs an IPC syscall, returning the message registers set by the kernel
+///
+/// # Safety
+///
+/// Performing a syscall is inherently unsafe, follow the syscall
+/// documentation to guarantee proper usage and soundness.
+pub unsafe fn ipc(cap: CapId, args: SyscallArgs) -> Result<[usize; MESSAGE_REGISTERS], CapError> {
+    let mut msg = [0; MESSAGE_REGISTERS];
+    let result = unsafe {
+        raw_ipc(
+            u32::from(cap).try_into().unwrap(),
+            args.op(),
+            args.args().0,
+            args.args().1,
+            args.args().2,
+            args.args().3,
+            &mut msg,
+        )
+    };
+    match usize::try_from(result) {
+        Ok(_) => Ok(msg),
+        Err(_) => Err(CapError::try_from((-result) as u8).unwrap()),
+    }
+}
+
 #[derive(Debug, Copy, Clone, TryFromPrimitive, IntoPrimitive)]
 #[repr(usize)]
 pub enum RawOperation {
@@ -75,6 +152,32 @@ pub enum RawOperation {
     PageTableUnlink,
     MemoryRegionRetype,
     MemoryRegionSplit,
+    ClockMonotonic,
+    EndpointCall,
+    EndpointReply,
+    EndpointReplyRecv,
+    NotificationSignal,
+    NotificationWait,
+    NotificationPoll,
+    ThreadSetIpcBuffer,
+    ComponentInvoke,
+    ComponentReturn,
+    PageTableMap,
+    PageTableChangeFlags,
+    MemoryRegionUntype,
+    DeviceRegionSplit,
+    IoPortsSplit,
+    IoPortsGrant,
+    IoPortsRevoke,
+    IrqControlGet,
+    IrqHandlerBind,
+    IrqHandlerUnbind,
+    IrqHandlerWait,
+    IrqHandlerAck,
+    PciFunction,
+    PciFunctionRead,
+    PciFunctionWrite,
+    PciFunctionBar,
 }
 
 #[derive(Debug, Copy, Clone, TryFromPrimitive, IntoPrimitive)]
@@ -88,6 +191,9 @@ pub enum CapError {
     FrameOutsideOfRegion,
     FrameNotUser,
     Internal,
+    WouldBlock,
+    InvalidUserAddress,
+    FrameNotDevice,
 }
 
 #[derive(Debug, Copy, Clone, TryFromPrimitive, IntoPrimitive)]
@@ -96,6 +202,17 @@ pub enum ResourceType {
     CapabilityTable = 0,
     ThreadControlBlock,
     PageTable,
+    Clock,
+    Endpoint,
+    Notification,
+    Component,
+    MemoryRegion,
+    DeviceRegion,
+    IoPorts,
+    IrqControl,
+    IrqHandler,
+    Pci,
+    PciFunction,
 }
 
 impl<T: TryFromPrimitive> From<TryFromPrimitiveError<T>> for CapError {
@@ -165,6 +282,12 @@ pub struct CapId(u32);
 
 pub struct OutOfBounds;
 
+impl CapId {
+    pub const fn new(id: u32) -> Self {
+        Self(id)
+    }
+}
+
 impl From<u32> for CapId {
     fn from(value: u32) -> Self {
         Self(value)
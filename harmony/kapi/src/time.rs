@@ -0,0 +1,84 @@
+//! The shared time page.
+//!
+//! The kernel maps a read-only page with the clock's conversion parameters
+//! into every address space so the monotonic clock can be read without a
+//! syscall.
+
+/// Address of the time page in every address space.
+pub const TIME_PAGE_ADDRESS: usize = 0xFFFF_C000_0000_0000;
+
+/// The hardware source backing the monotonic clock.
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClockSource {
+    /// The clock can only be read through the clock capability.
+    Syscall = 0,
+    /// The clock is derived from the invariant TSC.
+    Tsc = 1,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct TimePage {
+    pub source: ClockSource,
+    /// Right shift applied after scaling the TSC by `mult`.
+    pub shift: u32,
+    /// TSC value at which the monotonic clock reads 0.
+    pub tsc_base: u64,
+    /// Fixed-point multiplier converting TSC ticks into nanoseconds.
+    pub mult: u64,
+}
+
+impl TimePage {
+    /// Builds the conversion parameters for a TSC running at `tsc_hz`.
+    pub const fn for_tsc(tsc_base: u64, tsc_hz: u64) -> Self {
+        const SHIFT: u32 = 32;
+        Self {
+            source: ClockSource::Tsc,
+            shift: SHIFT,
+            tsc_base,
+            mult: (1_000_000_000u128 << SHIFT).div_ceil(tsc_hz as u128) as u64,
+        }
+    }
+
+    /// Converts a TSC reading into monotonic nanoseconds.
+    pub fn tsc_to_nanos(&self, tsc: u64) -> u64 {
+        let delta = tsc.saturating_sub(self.tsc_base) as u128;
+        ((delta * self.mult as u128) >> self.shift) as u64
+    }
+
+    /// Reads the monotonic clock in nanoseconds.
+    ///
+    /// Returns `None` if the clock must be read through the clock capability.
+    pub fn now(&self) -> Option<u64> {
+        match self.source {
+            ClockSource::Syscall => None,
+            // SAFETY: Reading the TSC has no side effects.
+            ClockSource::Tsc => Some(self.tsc_to_nanos(unsafe { core::arch::x86_64::_rdtsc() })),
+        }
+    }
+
+    /// Returns the time page mapped by the kernel.
+    ///
+    /// # Safety
+    ///
+    /// Must be called from an address space set up by the kernel.
+    pub unsafe fn get() -> &'static Self {
+        unsafe { &*(TIME_PAGE_ADDRESS as *const Self) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tsc_conversion() {
+        let page = TimePage::for_tsc(1000, 2_000_000_000);
+        assert_eq!(page.tsc_to_nanos(1000), 0);
+        assert_eq!(page.tsc_to_nanos(3000), 1000);
+        assert_eq!(page.tsc_to_nanos(2_000_001_000), 1_000_000_000);
+        // Readings before the base saturate to 0.
+        assert_eq!(page.tsc_to_nanos(0), 0);
+    }
+}
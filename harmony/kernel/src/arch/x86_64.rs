@@ -1,27 +1,91 @@
 use core::arch::asm;
 
+use x86_64_impl::registers::model_specific::{Efer, EferFlags};
+
 use crate::arch::timer::Pit8253;
 
+pub mod acpi;
+pub mod apic;
 pub mod bootup;
+pub mod clock;
 pub mod exec;
 pub mod instructions;
 pub mod interrupts;
+pub mod io_ports;
 pub mod paging;
+pub mod pci;
 pub mod timer;
+pub mod user;
 
 mod gdt;
 mod registers;
 
+const BOOT_STACK_SIZE: usize = 0x32000;
+
+/// Stack the kernel boots on. Unlike the bootloader's stack, it's part of the
+/// kernel image and survives the reclaim of the bootloader's memory.
+#[repr(align(16))]
+struct BootStack([u8; BOOT_STACK_SIZE]);
+static mut BOOT_STACK: BootStack = BootStack([0; BOOT_STACK_SIZE]);
+
+/// Leaves the bootloader's stack for good, calling `entry` on the kernel's
+/// boot stack.
+///
+/// # Safety
+///
+/// Must only be called once.
+pub unsafe fn enter_boot_stack(entry: extern "sysv64" fn() -> !) -> ! {
+    // SAFETY: Nothing else uses the boot stack, and the bootloader's stack
+    // is never returned to.
+    unsafe {
+        let top = core::ptr::addr_of!(BOOT_STACK.0) as usize + BOOT_STACK_SIZE;
+        asm!(
+            "mov rsp, {top}",
+            "call {entry}",
+            top = in(reg) top,
+            entry = in(reg) entry,
+            options(noreturn),
+        );
+    }
+}
+
 pub fn init() {
+    nxe_enable();
     gdt::init();
     interrupts::init();
+    paging::tlb::init();
+    paging::pat::init();
+    user::init();
     let mut _timer = unsafe { Pit8253::steal().into_timer(5966) };
     log::info!("PIT Timer is initialized");
     sce_enable();
+    acpi::init();
 
     log::info!("All x86-64 subsystems initialized");
 }
 
+/// Initializes the subsystems that depend on the retype table.
+pub fn late_init() {
+    paging::kernel_image::enforce_wx();
+    apic::init();
+    interrupts::mask_legacy_timer();
+    log::info!("LAPIC timer is initialized");
+    clock::init();
+    log::info!("Monotonic clock is initialized");
+    pci::init();
+    #[cfg(feature = "KPTI")]
+    paging::kpti::init();
+}
+
+fn nxe_enable() {
+    // SAFETY: Only honours the NO_EXECUTE bit of the page tables, which is
+    // never set on code the kernel runs.
+    unsafe {
+        Efer::update(|efer| efer.insert(EferFlags::NO_EXECUTE_ENABLE));
+    }
+    log::info!("Enabled no-execute pages");
+}
+
 fn sce_enable() {
     // SAFETY: Nothing special, just enabling Syscall extension.
     unsafe {
@@ -0,0 +1,628 @@
+//! ACPI table discovery.
+//!
+//! A small, allocation-free parser for the firmware tables. The RSDP is
+//! provided by Limine and every table is read in place through the higher-half
+//! direct mapping.
+
+use limine::request::RsdpRequest;
+use sync::cell::AtomicLazyCell;
+use x86_64_impl::instructions::port::Port;
+
+use crate::arch::paging::{PhysAddr, VirtAddr};
+use crate::PMO;
+
+/// The system's ACPI tables.
+pub static ACPI: AtomicLazyCell<Acpi> = AtomicLazyCell::new(|| {
+    #[used]
+    static RSDP: RsdpRequest = RsdpRequest::new();
+
+    let address = RSDP
+        .get_response()
+        .expect("Missing RSDP response from limine")
+        .address() as usize;
+    // Older base revisions hand us a higher-half pointer while newer ones
+    // provide the physical address.
+    let rsdp = if address >= PMO.as_usize() {
+        VirtAddr::new(address)
+    } else {
+        PhysAddr::new(address as u64).to_virtual()
+    };
+    // SAFETY: Limine guarantees the address points to the RSDP.
+    unsafe { Acpi::from_rsdp(rsdp) }.expect("Invalid ACPI tables")
+});
+
+/// Parses the ACPI tables and logs what was found.
+pub fn init() {
+    let acpi = ACPI.get();
+    for table in acpi.tables() {
+        log::debug!(
+            "Found ACPI table {}",
+            core::str::from_utf8(&table.signature()).unwrap_or("????")
+        );
+    }
+    if let Some(madt) = acpi.madt() {
+        let cores = madt
+            .entries()
+            .filter(|entry| {
+                matches!(
+                    entry,
+                    MadtEntry::LocalApic { flags, .. } | MadtEntry::LocalX2Apic { flags, .. }
+                    if flags & MadtEntry::PROCESSOR_ENABLED != 0
+                )
+            })
+            .count();
+        log::info!("ACPI reports {cores} enabled core(s)");
+    }
+    log::info!("ACPI tables initialized");
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AcpiError {
+    BadSignature,
+    BadChecksum,
+    UnsupportedRevision,
+}
+
+/// Sums the bytes in the region, valid ACPI structures add up to 0.
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |acc, byte| acc.wrapping_add(*byte))
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    _reserved: [u8; 3],
+}
+
+impl Rsdp {
+    const SIGNATURE: [u8; 8] = *b"RSD PTR ";
+    const V1_LENGTH: usize = 20;
+}
+
+/// The header shared by every system description table.
+#[repr(C, packed)]
+pub struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+impl SdtHeader {
+    pub fn signature(&self) -> [u8; 4] {
+        self.signature
+    }
+
+    pub fn length(&self) -> usize {
+        self.length as usize
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.revision
+    }
+
+    /// The entire table, including the header.
+    pub fn as_bytes(&self) -> &[u8] {
+        // SAFETY: Tables are validated to span `length` bytes.
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, self.length()) }
+    }
+
+    /// The table contents following the header.
+    pub fn body(&self) -> &[u8] {
+        &self.as_bytes()[core::mem::size_of::<Self>()..]
+    }
+
+    /// Validates the table located at the physical address.
+    ///
+    /// # Safety
+    ///
+    /// The address must point to a mapped ACPI table.
+    unsafe fn from_physical(addr: u64) -> Result<&'static Self, AcpiError> {
+        let header: *const Self = PhysAddr::new(addr).to_virtual().as_ptr();
+        // SAFETY: Precondition.
+        let header = unsafe { &*header };
+        if header.length() < core::mem::size_of::<Self>() {
+            return Err(AcpiError::BadSignature);
+        }
+        if checksum(header.as_bytes()) != 0 {
+            return Err(AcpiError::BadChecksum);
+        }
+        Ok(header)
+    }
+}
+
+/// ACPI generic address structure.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+    pub const PCI_CONFIG: u8 = 2;
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            address_space: bytes[0],
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address: read_u64(bytes, 4),
+        }
+    }
+}
+
+/// Entry point to the ACPI tables.
+pub struct Acpi {
+    root: &'static SdtHeader,
+    extended: bool,
+}
+
+impl Acpi {
+    /// Parses the tables starting at the RSDP.
+    ///
+    /// # Safety
+    ///
+    /// The address must point to a valid RSDP and all ACPI tables must be
+    /// reachable through the direct mapping.
+    pub unsafe fn from_rsdp(rsdp: VirtAddr) -> Result<Self, AcpiError> {
+        let ptr: *const Rsdp = rsdp.as_ptr();
+        // SAFETY: Precondition.
+        let rsdp = unsafe { &*ptr };
+        if rsdp.signature != Rsdp::SIGNATURE {
+            return Err(AcpiError::BadSignature);
+        }
+        // SAFETY: The first 20 bytes are present in all revisions.
+        let v1 = unsafe { core::slice::from_raw_parts(ptr as *const u8, Rsdp::V1_LENGTH) };
+        if checksum(v1) != 0 {
+            return Err(AcpiError::BadChecksum);
+        }
+        let (root, extended) = match rsdp.revision {
+            0 => (rsdp.rsdt_address as u64, false),
+            2.. => {
+                // SAFETY: Revision 2 guarantees the extended fields exist.
+                let full =
+                    unsafe { core::slice::from_raw_parts(ptr as *const u8, rsdp.length as usize) };
+                if checksum(full) != 0 {
+                    return Err(AcpiError::BadChecksum);
+                }
+                (rsdp.xsdt_address, true)
+            }
+            1 => return Err(AcpiError::UnsupportedRevision),
+        };
+        // SAFETY: Root table address comes from a valid RSDP.
+        let root = unsafe { SdtHeader::from_physical(root)? };
+        let expected = if extended { b"XSDT" } else { b"RSDT" };
+        if &root.signature() != expected {
+            return Err(AcpiError::BadSignature);
+        }
+        Ok(Self { root, extended })
+    }
+
+    /// Iterates over all the valid tables referenced by the RSDT/XSDT.
+    pub fn tables(&self) -> impl Iterator<Item = &'static SdtHeader> {
+        let body = self.root.body();
+        let width = if self.extended { 8 } else { 4 };
+        let extended = self.extended;
+        body.chunks_exact(width).filter_map(move |entry| {
+            let addr = if extended {
+                read_u64(entry, 0)
+            } else {
+                read_u32(entry, 0) as u64
+            };
+            // SAFETY: Entry addresses in the root table point to ACPI tables.
+            match unsafe { SdtHeader::from_physical(addr) } {
+                Ok(table) => Some(table),
+                Err(e) => {
+                    log::warn!("Skipping invalid ACPI table at {addr:#X}: {e:?}");
+                    None
+                }
+            }
+        })
+    }
+
+    /// Finds the first table with the given signature.
+    pub fn find(&self, signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+        self.tables().find(|table| &table.signature() == signature)
+    }
+
+    /// Multiple APIC description table.
+    pub fn madt(&self) -> Option<Madt> {
+        self.find(b"APIC").and_then(Madt::new)
+    }
+
+    /// High precision event timer table.
+    pub fn hpet(&self) -> Option<Hpet> {
+        self.find(b"HPET").and_then(Hpet::new)
+    }
+
+    /// Fixed ACPI description table.
+    pub fn fadt(&self) -> Option<Fadt> {
+        self.find(b"FACP").and_then(Fadt::new)
+    }
+
+    /// PCI Express memory-mapped configuration table.
+    pub fn mcfg(&self) -> Option<Mcfg> {
+        self.find(b"MCFG").and_then(Mcfg::new)
+    }
+}
+
+/// Multiple APIC description table.
+#[derive(Copy, Clone)]
+pub struct Madt {
+    body: &'static [u8],
+}
+
+impl Madt {
+    const HEADER_LENGTH: usize = 8;
+
+    fn new(table: &'static SdtHeader) -> Option<Self> {
+        Self::from_body(table.body())
+    }
+
+    fn from_body(body: &'static [u8]) -> Option<Self> {
+        (body.len() >= Self::HEADER_LENGTH).then_some(Self { body })
+    }
+
+    /// Physical address of the local APIC.
+    pub fn local_apic_address(&self) -> u64 {
+        let address = self.entries().find_map(|entry| match entry {
+            MadtEntry::LocalApicAddressOverride { address } => Some(address),
+            _ => None,
+        });
+        address.unwrap_or(read_u32(self.body, 0) as u64)
+    }
+
+    /// Whether the system also has legacy 8259 PICs.
+    pub fn has_8259(&self) -> bool {
+        read_u32(self.body, 4) & 1 != 0
+    }
+
+    pub fn entries(&self) -> MadtEntries {
+        MadtEntries {
+            bytes: &self.body[Self::HEADER_LENGTH..],
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        address: u32,
+        gsi_base: u32,
+    },
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    LocalApicNmi {
+        processor_id: u8,
+        flags: u16,
+        lint: u8,
+    },
+    LocalApicAddressOverride {
+        address: u64,
+    },
+    LocalX2Apic {
+        x2apic_id: u32,
+        flags: u32,
+        processor_uid: u32,
+    },
+    Unknown {
+        kind: u8,
+    },
+}
+
+impl MadtEntry {
+    pub const PROCESSOR_ENABLED: u32 = 1;
+
+    fn parse(kind: u8, data: &[u8]) -> Option<Self> {
+        let entry = match kind {
+            0 if data.len() >= 6 => Self::LocalApic {
+                processor_id: data[0],
+                apic_id: data[1],
+                flags: read_u32(data, 2),
+            },
+            1 if data.len() >= 10 => Self::IoApic {
+                id: data[0],
+                address: read_u32(data, 2),
+                gsi_base: read_u32(data, 6),
+            },
+            2 if data.len() >= 8 => Self::InterruptSourceOverride {
+                bus: data[0],
+                source: data[1],
+                gsi: read_u32(data, 2),
+                flags: read_u16(data, 6),
+            },
+            4 if data.len() >= 4 => Self::LocalApicNmi {
+                processor_id: data[0],
+                flags: read_u16(data, 1),
+                lint: data[3],
+            },
+            5 if data.len() >= 10 => Self::LocalApicAddressOverride {
+                address: read_u64(data, 2),
+            },
+            9 if data.len() >= 14 => Self::LocalX2Apic {
+                x2apic_id: read_u32(data, 2),
+                flags: read_u32(data, 6),
+                processor_uid: read_u32(data, 10),
+            },
+            0 | 1 | 2 | 4 | 5 | 9 => return None,
+            kind => Self::Unknown { kind },
+        };
+        Some(entry)
+    }
+}
+
+pub struct MadtEntries {
+    bytes: &'static [u8],
+}
+
+impl Iterator for MadtEntries {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let [kind, length, ..] = *self.bytes else {
+                return None;
+            };
+            let length = length as usize;
+            if length < 2 || length > self.bytes.len() {
+                log::warn!("Malformed MADT entry");
+                self.bytes = &[];
+                return None;
+            }
+            let data = &self.bytes[2..length];
+            self.bytes = &self.bytes[length..];
+            if let Some(entry) = MadtEntry::parse(kind, data) {
+                return Some(entry);
+            }
+        }
+    }
+}
+
+/// High precision event timer description.
+#[derive(Debug, Copy, Clone)]
+pub struct Hpet {
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+impl Hpet {
+    fn new(table: &'static SdtHeader) -> Option<Self> {
+        let body = table.body();
+        if body.len() < 20 {
+            return None;
+        }
+        Some(Self {
+            event_timer_block_id: read_u32(body, 0),
+            base_address: GenericAddress::from_bytes(&body[4..16]),
+            hpet_number: body[16],
+            minimum_tick: read_u16(body, 17),
+            page_protection: body[19],
+        })
+    }
+}
+
+/// Fixed ACPI description table, limited to power management and reset.
+#[derive(Copy, Clone)]
+pub struct Fadt {
+    table: &'static SdtHeader,
+}
+
+impl Fadt {
+    const RESET_REG_SUPPORTED: u32 = 1 << 10;
+
+    fn new(table: &'static SdtHeader) -> Option<Self> {
+        (table.length() >= 116).then_some(Self { table })
+    }
+
+    fn bytes(&self) -> &'static [u8] {
+        self.table.as_bytes()
+    }
+
+    /// System interrupt the SCI is wired to in 8259 mode.
+    pub fn sci_interrupt(&self) -> u16 {
+        read_u16(self.bytes(), 46)
+    }
+
+    /// Port of the PM1a control block.
+    pub fn pm1a_control_block(&self) -> u32 {
+        read_u32(self.bytes(), 64)
+    }
+
+    /// Port of the PM1b control block, if present.
+    pub fn pm1b_control_block(&self) -> Option<u32> {
+        let port = read_u32(self.bytes(), 68);
+        (port != 0).then_some(port)
+    }
+
+    /// Port of the ACPI power management timer, if present.
+    pub fn pm_timer_block(&self) -> Option<u32> {
+        let port = read_u32(self.bytes(), 76);
+        (port != 0).then_some(port)
+    }
+
+    /// Index of the RTC century register, if present.
+    pub fn century(&self) -> Option<u8> {
+        let century = self.bytes()[108];
+        (century != 0).then_some(century)
+    }
+
+    /// IA-PC boot architecture flags.
+    pub fn boot_architecture_flags(&self) -> u16 {
+        read_u16(self.bytes(), 109)
+    }
+
+    pub fn flags(&self) -> u32 {
+        read_u32(self.bytes(), 112)
+    }
+
+    /// The reset register and the value to write to it.
+    pub fn reset_register(&self) -> Option<(GenericAddress, u8)> {
+        if self.table.length() < 129 || self.flags() & Self::RESET_REG_SUPPORTED == 0 {
+            return None;
+        }
+        let bytes = self.bytes();
+        Some((GenericAddress::from_bytes(&bytes[116..128]), bytes[128]))
+    }
+
+    /// Resets the system through the FADT reset register.
+    ///
+    /// Only returns if the reset register is missing or isn't in I/O space.
+    pub fn reset(&self) {
+        let Some((register, value)) = self.reset_register() else {
+            return;
+        };
+        if register.address_space != GenericAddress::SYSTEM_IO {
+            log::warn!("Unsupported reset register: {register:?}");
+            return;
+        }
+        let Ok(port) = u16::try_from(register.address) else {
+            return;
+        };
+        // SAFETY: The firmware told us this resets the system.
+        unsafe {
+            Port::new(port).write(value);
+        }
+    }
+}
+
+/// PCI Express memory-mapped configuration table.
+#[derive(Copy, Clone)]
+pub struct Mcfg {
+    body: &'static [u8],
+}
+
+/// An ECAM region for a range of PCI buses in a segment group.
+#[derive(Debug, Copy, Clone)]
+pub struct EcamRegion {
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl Mcfg {
+    const RESERVED_LENGTH: usize = 8;
+    const ENTRY_LENGTH: usize = 16;
+
+    fn new(table: &'static SdtHeader) -> Option<Self> {
+        let body = table.body();
+        (body.len() >= Self::RESERVED_LENGTH).then_some(Self { body })
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = EcamRegion> {
+        self.body[Self::RESERVED_LENGTH..]
+            .chunks_exact(Self::ENTRY_LENGTH)
+            .map(|entry| EcamRegion {
+                base_address: read_u64(entry, 0),
+                segment_group: read_u16(entry, 8),
+                start_bus: entry[10],
+                end_bus: entry[11],
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn madt_entries() {
+        #[rustfmt::skip]
+        static BODY: [u8; 8 + 8 + 12 + 10 + 4] = [
+            // Local APIC address and flags
+            0x00, 0x00, 0xE0, 0xFE, 0x01, 0x00, 0x00, 0x00,
+            // Local APIC
+            0, 8, 0, 1, 0x01, 0x00, 0x00, 0x00,
+            // IO APIC
+            1, 12, 2, 0, 0x00, 0x00, 0xC0, 0xFE, 0x00, 0x00, 0x00, 0x00,
+            // Interrupt source override
+            2, 10, 0, 0, 2, 0, 0, 0, 0, 0,
+            // Unknown
+            0x7F, 4, 0, 0,
+        ];
+        let madt = Madt::from_body(&BODY).unwrap();
+        assert_eq!(madt.local_apic_address(), 0xFEE0_0000);
+        assert!(madt.has_8259());
+        let mut entries = madt.entries();
+        assert!(matches!(
+            entries.next(),
+            Some(MadtEntry::LocalApic {
+                processor_id: 0,
+                apic_id: 1,
+                flags: 1
+            })
+        ));
+        assert!(matches!(
+            entries.next(),
+            Some(MadtEntry::IoApic {
+                id: 2,
+                address: 0xFEC0_0000,
+                gsi_base: 0
+            })
+        ));
+        assert!(matches!(
+            entries.next(),
+            Some(MadtEntry::InterruptSourceOverride {
+                source: 0,
+                gsi: 2,
+                ..
+            })
+        ));
+        assert!(matches!(
+            entries.next(),
+            Some(MadtEntry::Unknown { kind: 0x7F })
+        ));
+        assert!(entries.next().is_none());
+    }
+
+    #[test_case]
+    fn checksum_wraps() {
+        assert_eq!(checksum(&[0xFF, 0x01]), 0);
+        assert_eq!(checksum(&[0x10, 0x20]), 0x30);
+    }
+}
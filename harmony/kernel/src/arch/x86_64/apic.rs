@@ -0,0 +1,294 @@
+//! Local APIC and its timer.
+//!
+//! Each core's LAPIC timer is used as a one-shot deadline timer. When the CPU
+//! supports it, the TSC-deadline mode is used so deadlines are programmed in
+//! TSC ticks directly. Otherwise, the timer runs in one-shot mode with its
+//! frequency calibrated against the PIT.
+
+use core::cell::Cell;
+
+use sync::cell::{AtomicLazyCell, AtomicOnceCell};
+use x86_64_impl::registers::model_specific::Msr;
+
+use super::instructions::rdtsc;
+use super::interrupts::{LAPIC_TIMER_INT, SPURIOUS_INT};
+use super::paging::{mmio, PhysAddr, VirtAddr};
+use super::timer::PitChannel2;
+use crate::core_local::CoreLocal;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// Number of PIT ticks used to calibrate the timers (~10ms).
+const CALIBRATION_TICKS: u16 = 11932;
+
+/// Callback to run when the core's pending deadline expires.
+type ExpiryHandler = Cell<Option<fn()>>;
+
+static LAPIC: AtomicOnceCell<LocalApic> = AtomicOnceCell::new();
+static CALIBRATION: AtomicOnceCell<Calibration> = AtomicOnceCell::new();
+static EXPIRY_HANDLER: AtomicLazyCell<CoreLocal<ExpiryHandler>> =
+    AtomicLazyCell::new(|| CoreLocal::new_with(|_| Cell::new(None)));
+
+#[derive(Debug, Copy, Clone)]
+#[repr(usize)]
+enum Register {
+    Id = 0x20,
+    Eoi = 0xB0,
+    SpuriousVector = 0xF0,
+    LvtTimer = 0x320,
+    TimerInitialCount = 0x380,
+    TimerCurrentCount = 0x390,
+    TimerDivide = 0x3E0,
+}
+
+/// Memory-mapped registers of the local APIC.
+///
+/// Every core maps its own LAPIC at the same physical address so a single
+/// mapping serves all cores.
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    const LVT_MASKED: u32 = 1 << 16;
+    const LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+    const SVR_ENABLE: u32 = 1 << 8;
+    /// Divides the bus clock by 16.
+    const DIVIDE_BY_16: u32 = 0b0011;
+
+    fn read(&self, register: Register) -> u32 {
+        let ptr: *const u32 = VirtAddr::new(self.base.as_usize() + register as usize).as_ptr();
+        // SAFETY: The register is within the mapped LAPIC page.
+        unsafe { ptr.read_volatile() }
+    }
+
+    fn write(&self, register: Register, value: u32) {
+        let ptr: *mut u32 = VirtAddr::new(self.base.as_usize() + register as usize).as_mut_ptr();
+        // SAFETY: The register is within the mapped LAPIC page.
+        unsafe { ptr.write_volatile(value) }
+    }
+
+    /// The APIC ID of the current core.
+    pub fn id(&self) -> u8 {
+        (self.read(Register::Id) >> 24) as u8
+    }
+
+    /// Signals the end of the interrupt currently being serviced.
+    pub fn end_of_interrupt(&self) {
+        self.write(Register::Eoi, 0);
+    }
+}
+
+/// Frequencies measured at boot.
+#[derive(Debug, Copy, Clone)]
+pub struct Calibration {
+    /// TSC frequency in Hz.
+    pub tsc_hz: u64,
+    /// LAPIC timer frequency in Hz (after the divider).
+    pub lapic_hz: u64,
+    /// Whether the timer is driven in TSC-deadline mode.
+    pub tsc_deadline: bool,
+    /// Whether the TSC runs at a constant rate in all power states.
+    pub invariant_tsc: bool,
+}
+
+impl Calibration {
+    /// Converts TSC ticks into nanoseconds.
+    pub fn tsc_to_nanos(&self, ticks: u64) -> u64 {
+        (ticks as u128 * 1_000_000_000 / self.tsc_hz as u128) as u64
+    }
+
+    /// Converts nanoseconds into TSC ticks.
+    pub fn nanos_to_tsc(&self, nanos: u64) -> u64 {
+        (nanos as u128 * self.tsc_hz as u128 / 1_000_000_000) as u64
+    }
+
+    fn nanos_to_lapic(&self, nanos: u64) -> u32 {
+        let ticks = nanos as u128 * self.lapic_hz as u128 / 1_000_000_000;
+        ticks.clamp(1, u32::MAX as u128) as u32
+    }
+}
+
+/// Returns the local APIC of the current core.
+pub fn local() -> &'static LocalApic {
+    LAPIC.get().expect("Local APIC is not initialized")
+}
+
+/// Returns the boot-time timer calibration.
+pub fn calibration() -> &'static Calibration {
+    CALIBRATION.get().expect("Timers are not calibrated")
+}
+
+/// Enables the current core's local APIC and sets up its timer.
+///
+/// The first call maps the LAPIC and calibrates the timers. Every core must call
+/// this once.
+pub fn init() {
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    // SAFETY: Enabling the APIC with its current base address.
+    let base = unsafe {
+        let value = apic_base.read();
+        apic_base.write(value | APIC_BASE_ENABLE);
+        value & APIC_BASE_MASK
+    };
+    if LAPIC.get().is_none() {
+        let base = mmio::map(PhysAddr::new(base), 0x1000).expect("Couldn't map the local APIC");
+        LAPIC.set(LocalApic { base }).unwrap();
+    }
+    let lapic = local();
+    lapic.write(
+        Register::SpuriousVector,
+        LocalApic::SVR_ENABLE | SPURIOUS_INT as u32,
+    );
+    lapic.write(Register::TimerDivide, LocalApic::DIVIDE_BY_16);
+    lapic.write(Register::LvtTimer, LocalApic::LVT_MASKED);
+
+    if CALIBRATION.get().is_none() {
+        let calibration = calibrate(lapic);
+        log::info!(
+            "Calibrated timers: TSC @ {} Hz, LAPIC @ {} Hz (TSC-deadline: {}, invariant TSC: {})",
+            calibration.tsc_hz,
+            calibration.lapic_hz,
+            calibration.tsc_deadline,
+            calibration.invariant_tsc,
+        );
+        CALIBRATION.set(calibration).unwrap();
+    }
+
+    let mode = if calibration().tsc_deadline {
+        LocalApic::LVT_TSC_DEADLINE
+    } else {
+        0
+    };
+    lapic.write(Register::LvtTimer, mode | LAPIC_TIMER_INT as u32);
+    log::info!("Initialized local APIC {}", lapic.id());
+}
+
+fn calibrate(lapic: &LocalApic) -> Calibration {
+    // SAFETY: Channel 2 is only used during calibration.
+    let mut pit = unsafe { PitChannel2::steal() };
+    pit.start(CALIBRATION_TICKS);
+    lapic.write(Register::TimerInitialCount, u32::MAX);
+    let tsc_start = rdtsc();
+    pit.wait();
+    let tsc_end = rdtsc();
+    let remaining = lapic.read(Register::TimerCurrentCount);
+    lapic.write(Register::TimerInitialCount, 0);
+
+    let elapsed = PitChannel2::ticks_to_nanos(CALIBRATION_TICKS);
+    let tsc_hz = (tsc_end - tsc_start) * 1_000_000_000 / elapsed;
+    let lapic_hz = (u32::MAX - remaining) as u64 * 1_000_000_000 / elapsed;
+
+    // SAFETY: CPUID is always available in long mode.
+    let tsc_deadline = unsafe { core::arch::x86_64::__cpuid(1) }.ecx & (1 << 24) != 0;
+    // SAFETY: CPUID is always available in long mode.
+    let invariant_tsc = unsafe {
+        let max_extended = core::arch::x86_64::__cpuid(0x8000_0000).eax;
+        max_extended >= 0x8000_0007 && core::arch::x86_64::__cpuid(0x8000_0007).edx & (1 << 8) != 0
+    };
+    Calibration {
+        tsc_hz,
+        lapic_hz,
+        tsc_deadline,
+        invariant_tsc,
+    }
+}
+
+/// Arms the current core's timer to fire once at the absolute TSC value.
+///
+/// The handler runs in interrupt context once the deadline expires. Arming
+/// the timer again replaces any pending deadline.
+pub fn arm_deadline(tsc: u64, handler: fn()) {
+    EXPIRY_HANDLER.get().get().set(Some(handler));
+    let calibration = calibration();
+    if calibration.tsc_deadline {
+        // SAFETY: The timer is in TSC-deadline mode.
+        unsafe {
+            Msr::new(IA32_TSC_DEADLINE).write(tsc.max(1));
+        }
+    } else {
+        let nanos = calibration.tsc_to_nanos(tsc.saturating_sub(rdtsc()));
+        local().write(
+            Register::TimerInitialCount,
+            calibration.nanos_to_lapic(nanos),
+        );
+    }
+}
+
+/// Arms the current core's timer to fire once after `nanos` nanoseconds.
+pub fn arm_oneshot(nanos: u64, handler: fn()) {
+    let deadline = rdtsc().saturating_add(calibration().nanos_to_tsc(nanos));
+    arm_deadline(deadline, handler);
+}
+
+/// Cancels the pending deadline on the current core, if any.
+pub fn disarm() {
+    if calibration().tsc_deadline {
+        // SAFETY: Writing 0 disarms the TSC deadline.
+        unsafe {
+            Msr::new(IA32_TSC_DEADLINE).write(0);
+        }
+    } else {
+        local().write(Register::TimerInitialCount, 0);
+    }
+    EXPIRY_HANDLER.get().get().set(None);
+}
+
+/// Called from the timer interrupt once the deadline expires.
+pub(super) fn timer_expired() {
+    let handler = EXPIRY_HANDLER.get().get().take();
+    local().end_of_interrupt();
+    if let Some(handler) = handler {
+        handler();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::arch::interrupts;
+
+    static FIRED: AtomicBool = AtomicBool::new(false);
+
+    fn fire() {
+        FIRED.store(true, Ordering::Relaxed);
+    }
+
+    /// Waits with interrupts enabled until the handler runs or `nanos` pass,
+    /// returning whether it ran.
+    fn wait_for_expiry(nanos: u64) -> bool {
+        let limit = rdtsc() + calibration().nanos_to_tsc(nanos);
+        // SAFETY: The test holds nothing the interrupt handlers use.
+        unsafe { interrupts::enable() };
+        while !FIRED.load(Ordering::Relaxed) && rdtsc() < limit {
+            core::hint::spin_loop();
+        }
+        interrupts::disable();
+        FIRED.swap(false, Ordering::Relaxed)
+    }
+
+    #[test_case]
+    fn oneshot_fires() {
+        arm_oneshot(100_000, fire);
+        assert!(wait_for_expiry(100_000_000));
+    }
+
+    #[test_case]
+    fn past_deadlines_fire() {
+        arm_deadline(rdtsc() / 2, fire);
+        assert!(wait_for_expiry(100_000_000));
+    }
+
+    #[test_case]
+    fn disarmed_timers_dont_fire() {
+        arm_oneshot(100_000, fire);
+        disarm();
+        assert!(!wait_for_expiry(10_000_000));
+    }
+}
@@ -7,7 +7,7 @@ use goblin::elf64::program_header::ProgramHeader;
 use super::paging::page_table::AnyPageTable;
 use crate::arch::exec::{ControlRegs, ExecCtx, Regs};
 use crate::arch::paging::page_table::{Addrspace, PageTableFlags};
-use crate::arch::paging::{Page, PhysAddr, RawFrame, VirtAddr, FRAME_SIZE, PAGE_SIZE};
+use crate::arch::paging::{Page, VirtAddr, PAGE_SIZE};
 use crate::bump_allocator::BumpAllocator;
 use crate::kptr::KPtr;
 
@@ -21,16 +21,10 @@ pub struct Process {
 pub enum LoadError {}
 
 impl Process {
-    pub fn load(
-        program: &[u8],
-        stack_pages: usize,
-        untyped_memory_offset: usize,
-        untyped_memory_length: usize,
-    ) -> Result<Self, LoadError> {
+    pub fn load(program: &[u8], stack_pages: usize, stack_top: usize) -> Result<Self, LoadError> {
         let mut fallocator = BumpAllocator::new();
-        assert!(untyped_memory_offset % PAGE_SIZE == 0);
-        assert!(untyped_memory_length % PAGE_SIZE == 0);
-        assert!(untyped_memory_offset + untyped_memory_length < 0xFFFF_8000_0000_0000);
+        assert!(stack_top % PAGE_SIZE == 0);
+        assert!(stack_top < 0xFFFF_8000_0000_0000);
         assert!(
             program.as_ptr() as usize % 16 == 0,
             "ELF must be aligned to 16 bytes"
@@ -67,7 +61,7 @@ impl Process {
         }
 
         log::debug!("Setting up stack pages");
-        let rsp = untyped_memory_offset;
+        let rsp = stack_top;
         for i in 0..stack_pages {
             let frame = fallocator.alloc_user_frame().unwrap().into_raw();
             let addr = rsp - PAGE_SIZE * (i + 1);
@@ -89,34 +83,10 @@ impl Process {
             }
         }
 
-        let untyped_memory_pages = untyped_memory_length / PAGE_SIZE;
-        log::debug!("Setting up {untyped_memory_pages} untyped memory pages");
-        for i in 0..untyped_memory_pages {
-            let frame = RawFrame::from_start_address(PhysAddr::new(i as u64 * FRAME_SIZE));
-            let page = Page::from_start_address(VirtAddr::new(
-                (frame.base().as_u64() + untyped_memory_offset as u64) as usize,
-            ));
-            // SAFETY: Mapping non-user accessible untyped pages.
-            // FIXME: Do this with huge pages? Since each l1 table can hold
-            // 256 entries, we are effectively wasting a bit over 1/256 frames
-            // in the system.
-            unsafe {
-                addrspace
-                    .map_to(
-                        page,
-                        frame,
-                        PageTableFlags::PRESENT,
-                        PageTableFlags::PRESENT,
-                        &mut fallocator,
-                    )
-                    .unwrap();
-            }
-        }
-
         log::info!("Initialized user process");
         Ok(Self {
             entry,
-            rsp: untyped_memory_offset as u64,
+            rsp: stack_top as u64,
             l4_table,
         })
     }
@@ -188,9 +158,9 @@ impl<'prog, 'head> Segment<'prog, 'head> {
                 PAGE_SIZE - vcurrent as usize % PAGE_SIZE,
             );
 
-            // SAFETY: Hopefully no bugs here...
+            // SAFETY: Hopefully no bugs here... The frame was zeroed when it
+            // was retyped.
             unsafe {
-                core::ptr::write_bytes(offset_page, 0, PAGE_SIZE);
                 if count > 0 {
                     core::ptr::copy(
                         self.program.as_ptr().add(fcurrent as usize),
@@ -0,0 +1,145 @@
+//! Monotonic system clock.
+//!
+//! The clock is derived from the invariant TSC whenever possible, in which case
+//! userspace can read it directly using the parameters on the shared time
+//! page. Otherwise, the HPET main counter is used and the time page tells
+//! userspace to go through the clock capability instead.
+
+use kapi::time::{ClockSource, TimePage, TIME_PAGE_ADDRESS};
+use sync::cell::AtomicOnceCell;
+
+use super::acpi::{GenericAddress, ACPI};
+use super::apic;
+use super::instructions::rdtsc;
+use super::paging::page_table::{AnyPageTable, PageTableFlags};
+use super::paging::{mmio, Page, PhysAddr, VirtAddr};
+use crate::bump_allocator::BumpAllocator;
+
+static SOURCE: AtomicOnceCell<Source> = AtomicOnceCell::new();
+
+enum Source {
+    Tsc(&'static TimePage),
+    Hpet(Hpet),
+}
+
+struct Hpet {
+    base: VirtAddr,
+    /// Counter period in femtoseconds.
+    period: u64,
+    counter_base: u64,
+}
+
+impl Hpet {
+    const CAPABILITIES: usize = 0x0;
+    const CONFIGURATION: usize = 0x10;
+    const MAIN_COUNTER: usize = 0xF0;
+    const ENABLE: u64 = 1;
+
+    fn register(&self, offset: usize) -> *mut u64 {
+        VirtAddr::new(self.base.as_usize() + offset).as_mut_ptr()
+    }
+
+    fn new(address: GenericAddress) -> Option<Self> {
+        if address.address_space != GenericAddress::SYSTEM_MEMORY {
+            return None;
+        }
+        let base = mmio::map(PhysAddr::new(address.address), 0x400).ok()?;
+        let mut hpet = Self {
+            base,
+            period: 0,
+            counter_base: 0,
+        };
+        // SAFETY: The registers are within the mapped HPET block.
+        unsafe {
+            hpet.period = hpet.register(Self::CAPABILITIES).read_volatile() >> 32;
+            let config = hpet.register(Self::CONFIGURATION);
+            config.write_volatile(config.read_volatile() | Self::ENABLE);
+        }
+        hpet.counter_base = hpet.counter();
+        Some(hpet)
+    }
+
+    fn counter(&self) -> u64 {
+        // SAFETY: The register is within the mapped HPET block.
+        unsafe { self.register(Self::MAIN_COUNTER).read_volatile() }
+    }
+
+    fn now(&self) -> u64 {
+        let ticks = self.counter().wrapping_sub(self.counter_base) as u128;
+        (ticks * self.period as u128 / 1_000_000) as u64
+    }
+}
+
+/// Returns the monotonic time in nanoseconds since the clock was initialized.
+pub fn now() -> u64 {
+    match SOURCE.get().expect("Clock is not initialized") {
+        Source::Tsc(page) => page.tsc_to_nanos(rdtsc()),
+        Source::Hpet(hpet) => hpet.now(),
+    }
+}
+
+/// Chooses the clock source and maps the shared time page.
+///
+/// Must run after the timers are calibrated and before any user address space
+/// is created, as those only get a shallow copy of the kernel entries.
+pub fn init() {
+    let calibration = apic::calibration();
+    let hpet = if calibration.invariant_tsc {
+        None
+    } else {
+        ACPI.hpet().and_then(|hpet| Hpet::new(hpet.base_address))
+    };
+    let page = match hpet {
+        Some(_) => TimePage {
+            source: ClockSource::Syscall,
+            shift: 0,
+            tsc_base: 0,
+            mult: 0,
+        },
+        None => TimePage::for_tsc(rdtsc(), calibration.tsc_hz),
+    };
+
+    let frame = BumpAllocator::new()
+        .alloc_kernel_frame()
+        .expect("Couldn't allocate the time page")
+        .into_raw();
+    let time_page: *mut TimePage = frame.base().to_virtual().as_mut_ptr();
+    // SAFETY: The frame was just retyped (and zeroed) so nothing else
+    // references it.
+    unsafe {
+        time_page.write(page);
+    }
+
+    let table = AnyPageTable::current();
+    // SAFETY: The current table is the kernel's root table. The time page is
+    // the only user-accessible mapping under its top-level entry.
+    unsafe {
+        table
+            .as_addrspace()
+            .map_to(
+                Page::from_start_address(VirtAddr::new(TIME_PAGE_ADDRESS)),
+                frame,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::USER_ACCESSIBLE
+                    | PageTableFlags::NO_EXECUTE,
+                PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+                &mut BumpAllocator::new(),
+            )
+            .expect("Couldn't map the time page");
+    }
+
+    let source = match hpet {
+        Some(hpet) => {
+            log::info!("Using the HPET as the monotonic clock");
+            Source::Hpet(hpet)
+        }
+        None => {
+            log::info!("Using the TSC as the monotonic clock");
+            // SAFETY: The page is never written to again.
+            Source::Tsc(unsafe { &*time_page })
+        }
+    };
+    if SOURCE.set(source).is_err() {
+        panic!("Clock initialized twice");
+    }
+}
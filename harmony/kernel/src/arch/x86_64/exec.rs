@@ -2,6 +2,12 @@
 
 use core::arch::asm;
 
+#[cfg(feature = "KPTI")]
+use super::gdt;
+#[cfg(feature = "KPTI")]
+use super::paging::kpti;
+#[cfg(not(feature = "KPTI"))]
+use super::paging::tlb;
 use super::paging::RawFrame;
 
 pub trait SaveState: Sized {
@@ -93,17 +99,33 @@ impl ExecCtx {
         self.l4_frame = l4_frame;
     }
 
+    #[cfg(not(feature = "KPTI"))]
+    pub fn dispatch(&self) -> ! {
+        let cr3 = tlb::cr3_for(self.l4_frame);
+        self.dispatch_with_cr3(cr3)
+    }
+
+    #[cfg(feature = "KPTI")]
+    pub fn dispatch(&self) -> ! {
+        let cr3 = kpti::prepare_dispatch(self.l4_frame);
+        self.dispatch_with_cr3(cr3, gdt::interrupt_stack_end().as_u64())
+    }
+
+    #[cfg(not(feature = "KPTI"))]
     #[naked]
-    pub extern "sysv64" fn dispatch(&self) -> ! {
+    extern "sysv64" fn dispatch_with_cr3(&self, _cr3: u64) -> ! {
         // SAFETY: All ExecCtx must be safe to dispatch. Every l4_frame
         // must have the top half kernel mapped.
         unsafe {
             asm!(
                 "pop rax",
-                "mov rbx, cr3",            // Current CR3
-                "mov rax, [rdi + 8 * 18]", // New cr3
-                "cmp rax, rbx",
-                "mov cr3, rax",
+                "mov rbx, cr3", // Current CR3
+                "mov rax, rsi", // New CR3, without the no-flush bit
+                "btr rax, 63",
+                "cmp rax, rbx", // Same address space, keep the TLB as is
+                "je 2f",
+                "mov cr3, rsi",
+                "2:",
                 // Setup the segment selectors
                 "mov ax, (4 * 8) | 3",
                 "mov ds, ax",
@@ -138,4 +160,56 @@ impl ExecCtx {
             )
         }
     }
+
+    /// Dispatches on the user view, whose CR3 is only loaded right before
+    /// `iretq`. Everything after that must be mapped in the user view, hence
+    /// the switch to the interrupt stack and the entry text section.
+    #[cfg(feature = "KPTI")]
+    #[naked]
+    #[link_section = ".text.entry"]
+    extern "sysv64" fn dispatch_with_cr3(&self, _cr3: u64, _stack: u64) -> ! {
+        // SAFETY: All ExecCtx must be safe to dispatch. The user view maps
+        // this code and the interrupt stack.
+        unsafe {
+            asm!(
+                "mov rsp, rdx",
+                "push (4 * 8) | 3",  // SS
+                "push [rdi + 8*16]", // Push rsp
+                "push [rdi + 8*15]", // push rflags
+                "push (3 * 8) | 3",  // CS with RPL 3
+                "push [rdi + 8*17]", // Push the new instruction pointer
+                "push rsi",          // The user view's CR3
+                "push [rdi + 8*4]",  // And the RDI register
+                // Setup the segment selectors
+                "mov ax, (4 * 8) | 3",
+                "mov ds, ax",
+                "mov es, ax",
+                "mov fs, ax",
+                "mov gs, ax",
+                // Restore SCRATCH
+                "mov rax, [rdi + 8*0]",
+                "mov rcx, [rdi + 8*1]",
+                "mov rdx, [rdi + 8*2]",
+                "mov rsi, [rdi + 8*3]",
+                "mov r8, [rdi + 8*5]",
+                "mov r9, [rdi + 8*6]",
+                "mov r10, [rdi + 8*7]",
+                "mov r11, [rdi + 8*8]",
+                // Restore PRESEVED
+                "mov rbx, [rdi + 8*9]",
+                "mov rbp, [rdi + 8*10]",
+                "mov r12, [rdi + 8*11]",
+                "mov r13, [rdi + 8*12]",
+                "mov r14, [rdi + 8*13]",
+                "mov r15, [rdi + 8*14]",
+                // Switch to the user view, the context isn't mapped anymore
+                "mov rdi, [rsp + 8]",
+                "mov cr3, rdi",
+                "pop rdi",
+                "add rsp, 8",
+                "iretq",
+                options(noreturn)
+            )
+        }
+    }
 }
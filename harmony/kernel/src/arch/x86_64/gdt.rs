@@ -1,5 +1,7 @@
 //! Global descriptor table.
 
+use core::sync::atomic::AtomicU8;
+
 use sync::cell::AtomicLazyCell;
 use x86_64_impl::instructions::tables::load_tss;
 use x86_64_impl::registers::segmentation::{Segment, CS, DS, ES, FS, GS, SS};
@@ -14,6 +16,18 @@ pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 /// The TSS stack table index to be used for the Page Fault exception.
 pub const PAGE_FAULT_IST_INDEX: u16 = 1;
 
+/// Size of the I/O permission bitmap, one bit per port.
+pub const IO_BITMAP_SIZE: usize = (1 << 16) / 8;
+
+/// TSS followed by its I/O permission bitmap.
+#[repr(C)]
+struct Tss {
+    tss: TaskStateSegment,
+    /// A set bit denies access to the port from userspace. The CPU may read
+    /// a byte past the end of the bitmap, which must have all bits set.
+    io_bitmap: [AtomicU8; IO_BITMAP_SIZE + 1],
+}
+
 struct Selectors {
     code_selector: SegmentSelector,
     data_selector: SegmentSelector,
@@ -23,13 +37,27 @@ struct Selectors {
 }
 
 #[used]
+#[link_section = ".data.entry"]
 static mut INTERRUPT_STACK: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
 // FIXME: This needs to be per-core.
-static TSS: AtomicLazyCell<TaskStateSegment> = AtomicLazyCell::new(|| {
+#[link_section = ".data.entry"]
+static TSS: AtomicLazyCell<Tss> = AtomicLazyCell::new(|| {
     let mut tss = TaskStateSegment::new();
+    tss.iomap_base = core::mem::size_of::<TaskStateSegment>() as u16;
     tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
         const STACK_SIZE: usize = PAGE_SIZE;
         #[used]
+        #[link_section = ".data.entry"]
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        // SAFETY: Although it's a static mut, STACK is only used in this context.
+        let stack_start = VirtAddr::from_ptr(unsafe { STACK.as_slice() });
+        stack_start + STACK_SIZE as u64 // stack end.
+    };
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+        const STACK_SIZE: usize = PAGE_SIZE;
+        #[used]
+        #[link_section = ".data.entry"]
         static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
         // SAFETY: Although it's a static mut, STACK is only used in this context.
@@ -43,7 +71,10 @@ static TSS: AtomicLazyCell<TaskStateSegment> = AtomicLazyCell::new(|| {
         // on an interrupt/syscall so that we can save them in case of a thread dispatch *cough* *cough*.
         interrupt_stack_end()
     };
-    tss
+    Tss {
+        tss,
+        io_bitmap: [const { AtomicU8::new(0xFF) }; IO_BITMAP_SIZE + 1],
+    }
 });
 
 pub(super) fn interrupt_stack_end() -> VirtAddr {
@@ -51,13 +82,30 @@ pub(super) fn interrupt_stack_end() -> VirtAddr {
     start + PAGE_SIZE as u64
 }
 
+/// The I/O permission bitmap of the TSS, without the trailing byte.
+pub(super) fn io_bitmap() -> &'static [AtomicU8] {
+    &TSS.io_bitmap[..IO_BITMAP_SIZE]
+}
+
+/// Creates the descriptor of the TSS, with a limit covering the bitmap.
+fn tss_descriptor(tss: &'static Tss) -> Descriptor {
+    match Descriptor::tss_segment(&tss.tss) {
+        Descriptor::SystemSegment(low, high) => {
+            let limit = core::mem::size_of::<Tss>() as u64 - 1;
+            Descriptor::SystemSegment(low & !0xFFFF | limit, high)
+        }
+        Descriptor::UserSegment(_) => unreachable!(),
+    }
+}
+
+#[link_section = ".data.entry"]
 static GDT: AtomicLazyCell<(GlobalDescriptorTable, Selectors)> = AtomicLazyCell::new(|| {
     let mut gdt = GlobalDescriptorTable::new();
     let code_selector = gdt.append(Descriptor::kernel_code_segment());
     let data_selector = gdt.append(Descriptor::kernel_data_segment());
     let user_code_selector = gdt.append(Descriptor::user_code_segment());
     let user_data_selector = gdt.append(Descriptor::user_data_segment());
-    let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+    let tss_selector = gdt.append(tss_descriptor(&TSS));
     (
         gdt,
         Selectors {
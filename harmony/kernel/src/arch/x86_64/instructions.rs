@@ -13,3 +13,9 @@ pub fn nop() {
         core::arch::asm!("nop", options(nomem, preserves_flags, nostack));
     }
 }
+
+/// Reads the time-stamp counter.
+pub fn rdtsc() -> u64 {
+    // SAFETY: Reading the TSC has no side effects.
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
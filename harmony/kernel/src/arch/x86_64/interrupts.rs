@@ -1,13 +1,69 @@
 use core::arch::asm;
 
 use pic8259::ChainedPics;
-use sync::cell::AtomicLazyCell;
-use x86_64_impl::structures::idt::InterruptDescriptorTable;
+use sync::cell::{AtomicLazyCell, AtomicOnceCell};
+use x86_64_impl::structures::idt::{HandlerFunc, InterruptDescriptorTable};
 use x86_64_impl::PrivilegeLevel;
 
 use crate::arch::x86_64::{self, gdt};
 
+/// Switches to the kernel's tables when entering from userspace, `$cs` being
+/// the offset of the saved code segment from the stack pointer.
+#[cfg(feature = "KPTI")]
+macro_rules! switch_to_kernel_tables {
+    ($cs:literal) => {
+        concat!(
+            "test byte ptr [rsp + ",
+            $cs,
+            "], 3\n",
+            "jz 2f\n",
+            "push rax\n",
+            "mov rax, [rip + KPTI_KERNEL_CR3]\n",
+            "mov cr3, rax\n",
+            "pop rax\n",
+            "2:\n",
+        )
+    };
+}
+
+/// Switches back to the user view when returning to userspace, `$cs` being
+/// the offset of the saved code segment from the stack pointer.
+#[cfg(feature = "KPTI")]
+macro_rules! switch_to_user_tables {
+    ($cs:literal) => {
+        concat!(
+            "test byte ptr [rsp + ",
+            $cs,
+            "], 3\n",
+            "jz 3f\n",
+            "push rax\n",
+            "mov rax, [rip + KPTI_USER_CR3]\n",
+            "mov cr3, rax\n",
+            "pop rax\n",
+            "3:\n",
+        )
+    };
+}
+
+#[cfg(not(feature = "KPTI"))]
+macro_rules! switch_to_kernel_tables {
+    ($cs:literal) => {
+        ""
+    };
+}
+
+#[cfg(not(feature = "KPTI"))]
+macro_rules! switch_to_user_tables {
+    ($cs:literal) => {
+        ""
+    };
+}
+
+#[cfg(feature = "KPTI")]
+mod entry;
 mod handlers;
+#[cfg(not(feature = "KPTI"))]
+use handlers as entry;
 pub use handlers::{IrqCtx, SyscallCtx};
 
 const PIC1_OFFSET: u8 = 32;
@@ -17,7 +73,16 @@ const PIC2_OFFSET: u8 = PIC1_OFFSET + 8;
 static mut PICS: ChainedPics = unsafe { ChainedPics::new(PIC1_OFFSET, PIC2_OFFSET) };
 
 const TIMER_INT: u8 = PIC1_OFFSET;
-const KEYBOARD_INT: u8 = PIC1_OFFSET + 1;
+/// Number of legacy IRQ lines.
+pub const IRQ_LINES: u8 = 16;
+/// Line the secondary PIC is chained on.
+const CASCADE_IRQ: u8 = 2;
+
+/// Called with the line of a device IRQ, once it has been masked.
+static IRQ_HANDLER: AtomicOnceCell<fn(u8)> = AtomicOnceCell::new();
+
+pub(super) const LAPIC_TIMER_INT: u8 = PIC2_OFFSET + 8;
+pub(super) const SPURIOUS_INT: u8 = 0xFF;
 
 const SYSCALL_INT: u8 = 0x80;
 
@@ -50,58 +115,80 @@ pub fn are_enabled() -> bool {
 
 /// Initializes the interrupt descriptor table.
 fn init_idt() {
+    #[link_section = ".data.entry"]
     static IDT: AtomicLazyCell<InterruptDescriptorTable> = AtomicLazyCell::new(|| {
         let mut idt = InterruptDescriptorTable::new();
         // Exceptions.
-        idt.breakpoint.set_handler_fn(handlers::breakpoint);
+        idt.breakpoint.set_handler_fn(entry::breakpoint);
         idt.general_protection_fault
-            .set_handler_fn(handlers::general_protection_fault);
-        idt.overflow.set_handler_fn(handlers::overflow);
-        idt.divide_error.set_handler_fn(handlers::divide_error);
+            .set_handler_fn(entry::general_protection_fault);
+        idt.overflow.set_handler_fn(entry::overflow);
+        idt.divide_error.set_handler_fn(entry::divide_error);
         idt.non_maskable_interrupt
-            .set_handler_fn(handlers::non_maskable_interrupt);
+            .set_handler_fn(entry::non_maskable_interrupt);
         idt.bound_range_exceeded
-            .set_handler_fn(handlers::bound_range_exceeded);
+            .set_handler_fn(entry::bound_range_exceeded);
         idt.bound_range_exceeded
-            .set_handler_fn(handlers::bound_range_exceeded);
-        idt.debug.set_handler_fn(handlers::debug);
-        idt.invalid_opcode.set_handler_fn(handlers::invalid_opcode);
+            .set_handler_fn(entry::bound_range_exceeded);
+        idt.debug.set_handler_fn(entry::debug);
+        idt.invalid_opcode.set_handler_fn(entry::invalid_opcode);
         idt.device_not_available
-            .set_handler_fn(handlers::device_not_available);
-        idt.invalid_tss.set_handler_fn(handlers::invalid_tss);
+            .set_handler_fn(entry::device_not_available);
+        idt.invalid_tss.set_handler_fn(entry::invalid_tss);
         idt.segment_not_present
-            .set_handler_fn(handlers::segment_not_present);
+            .set_handler_fn(entry::segment_not_present);
         idt.stack_segment_fault
-            .set_handler_fn(handlers::stack_segment_fault);
+            .set_handler_fn(entry::stack_segment_fault);
         idt.x87_floating_point
-            .set_handler_fn(handlers::x87_floating_point);
-        idt.alignment_check
-            .set_handler_fn(handlers::alignment_check);
-        idt.machine_check.set_handler_fn(handlers::machine_check);
+            .set_handler_fn(entry::x87_floating_point);
+        idt.alignment_check.set_handler_fn(entry::alignment_check);
+        idt.machine_check.set_handler_fn(entry::machine_check);
         idt.simd_floating_point
-            .set_handler_fn(handlers::simd_floating_point);
-        idt.virtualization.set_handler_fn(handlers::virtualization);
+            .set_handler_fn(entry::simd_floating_point);
+        idt.virtualization.set_handler_fn(entry::virtualization);
         idt.vmm_communication_exception
-            .set_handler_fn(handlers::vmm_communication_exception);
+            .set_handler_fn(entry::vmm_communication_exception);
         idt.security_exception
-            .set_handler_fn(handlers::security_exception);
+            .set_handler_fn(entry::security_exception);
         // SAFETY: Stack index provided is valid and only used for the double fault handler.
         unsafe {
             idt.double_fault
-                .set_handler_fn(handlers::double_fault)
+                .set_handler_fn(entry::double_fault)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
             idt.page_fault
-                .set_handler_fn(handlers::page_fault)
+                .set_handler_fn(entry::page_fault)
                 .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
         }
         // Syscall
         idt[SYSCALL_INT]
-            .set_handler_fn(handlers::syscall_interrupt)
+            .set_handler_fn(entry::syscall_interrupt)
             .set_privilege_level(PrivilegeLevel::Ring3);
 
         // PIC interrupts
-        idt[TIMER_INT].set_handler_fn(handlers::timer_interrupt);
-        idt[KEYBOARD_INT].set_handler_fn(handlers::keyboard_interrupt);
+        idt[TIMER_INT].set_handler_fn(entry::timer_interrupt);
+        let irqs: [(u8, HandlerFunc); 14] = [
+            (1, entry::irq1),
+            (3, entry::irq3),
+            (4, entry::irq4),
+            (5, entry::irq5),
+            (6, entry::irq6),
+            (7, entry::irq7),
+            (8, entry::irq8),
+            (9, entry::irq9),
+            (10, entry::irq10),
+            (11, entry::irq11),
+            (12, entry::irq12),
+            (13, entry::irq13),
+            (14, entry::irq14),
+            (15, entry::irq15),
+        ];
+        for (line, handler) in irqs {
+            idt[PIC1_OFFSET + line].set_handler_fn(handler);
+        }
+
+        // Local APIC interrupts
+        idt[LAPIC_TIMER_INT].set_handler_fn(entry::lapic_timer_interrupt);
+        idt[SPURIOUS_INT].set_handler_fn(entry::spurious_interrupt);
         idt
     });
     IDT.load();
@@ -113,11 +200,73 @@ pub fn init() {
     // SAFETY: PIC Initialization. We only initialize interrupts that we are currently handling.
     unsafe {
         PICS.initialize();
-        PICS.write_masks(0xFC, 0xFF);
+        // Only the timer and the cascade, device IRQs stay masked until their
+        // handler acknowledges them.
+        PICS.write_masks(0xFA, 0xFF);
     }
     log::info!("Interrupt tables initialized");
 }
 
+/// Masks the PIT's periodic tick once the LAPIC timer takes over.
+pub(super) fn mask_legacy_timer() {
+    // SAFETY: Only changes the mask of IRQ0.
+    unsafe {
+        let [primary, secondary] = PICS.read_masks();
+        PICS.write_masks(primary | 1, secondary);
+    }
+}
+
+/// Returns whether userspace can handle the IRQ `line`, all but the PIT and
+/// the cascade can.
+pub fn is_device_irq(line: u8) -> bool {
+    line < IRQ_LINES && line != 0 && line != CASCADE_IRQ
+}
+
+/// Sets the function called when a device IRQ fires.
+pub fn set_irq_handler(handler: fn(u8)) {
+    if IRQ_HANDLER.set(handler).is_err() {
+        panic!("IRQ handler set twice");
+    }
+}
+
+/// Unmasks the device IRQ `line`.
+pub fn unmask_irq(line: u8) {
+    debug_assert!(is_device_irq(line));
+    set_irq_masked(line, false);
+}
+
+pub fn mask_irq(line: u8) {
+    debug_assert!(is_device_irq(line));
+    set_irq_masked(line, true);
+}
+
+fn set_irq_masked(line: u8, masked: bool) {
+    let bit = 1 << (line % 8);
+    // SAFETY: Only changes the mask of the line.
+    unsafe {
+        let mut masks = PICS.read_masks();
+        let mask = &mut masks[line as usize / 8];
+        if masked {
+            *mask |= bit;
+        } else {
+            *mask &= !bit;
+        }
+        PICS.write_masks(masks[0], masks[1]);
+    }
+}
+
+/// Masks and acknowledges the device IRQ `line` before handing it over.
+fn irq_fired(line: u8) {
+    set_irq_masked(line, true);
+    // SAFETY: The line is masked, so it can't fire again until it's handled.
+    unsafe {
+        PICS.notify_end_of_interrupt(PIC1_OFFSET + line);
+    }
+    if let Some(handler) = IRQ_HANDLER.get() {
+        handler(line);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[test_case]
@@ -137,4 +286,13 @@ mod tests {
             }
         }
     }
+
+    #[test_case]
+    fn kernel_irqs_cant_be_handled() {
+        assert!(!super::is_device_irq(0));
+        assert!(!super::is_device_irq(super::CASCADE_IRQ));
+        assert!(super::is_device_irq(1));
+        assert!(super::is_device_irq(15));
+        assert!(!super::is_device_irq(super::IRQ_LINES));
+    }
 }
@@ -0,0 +1,57 @@
+//! Entry stubs for the exception handlers with KPTI.
+//!
+//! The handlers themselves live in the kernel's text, which isn't mapped in
+//! the user view, so the IDT points to these stubs instead. They switch to the
+//! kernel's tables when the exception came from userspace and jump to the
+//! handler with the stack untouched.
+
+use x86_64_impl::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+
+use super::handlers;
+pub(super) use super::handlers::{
+    irq1, irq10, irq11, irq12, irq13, irq14, irq15, irq3, irq4, irq5, irq6, irq7, irq8, irq9,
+    lapic_timer_interrupt, spurious_interrupt, syscall_interrupt, timer_interrupt,
+};
+
+macro_rules! entry_stub {
+    ($name:ident($($arg:ident: $ty:ty),*) $(-> $ret:ty)?, cs = $cs:literal) => {
+        #[naked]
+        #[link_section = ".text.entry"]
+        pub(super) extern "x86-interrupt" fn $name($($arg: $ty),*) $(-> $ret)? {
+            // SAFETY: Only switches tables before jumping to the handler, which
+            // gets the stack as the CPU left it.
+            unsafe {
+                core::arch::asm!(
+                    switch_to_kernel_tables!($cs),
+                    "jmp {handler}",
+                    handler = sym handlers::$name,
+                    options(noreturn),
+                )
+            }
+        }
+    };
+}
+
+entry_stub!(non_maskable_interrupt(_frame: InterruptStackFrame), cs = 8);
+entry_stub!(bound_range_exceeded(_frame: InterruptStackFrame), cs = 8);
+entry_stub!(debug(_frame: InterruptStackFrame), cs = 8);
+entry_stub!(invalid_opcode(_frame: InterruptStackFrame), cs = 8);
+entry_stub!(device_not_available(_frame: InterruptStackFrame), cs = 8);
+entry_stub!(invalid_tss(_frame: InterruptStackFrame, _code: u64), cs = 16);
+entry_stub!(segment_not_present(_frame: InterruptStackFrame, _code: u64), cs = 16);
+entry_stub!(stack_segment_fault(_frame: InterruptStackFrame, _code: u64), cs = 16);
+entry_stub!(x87_floating_point(_frame: InterruptStackFrame), cs = 8);
+entry_stub!(alignment_check(_frame: InterruptStackFrame, _code: u64), cs = 16);
+entry_stub!(machine_check(_frame: InterruptStackFrame) -> !, cs = 8);
+entry_stub!(simd_floating_point(_frame: InterruptStackFrame), cs = 8);
+entry_stub!(virtualization(_frame: InterruptStackFrame), cs = 8);
+entry_stub!(vmm_communication_exception(_frame: InterruptStackFrame, _code: u64), cs = 16);
+entry_stub!(security_exception(_frame: InterruptStackFrame, _code: u64), cs = 16);
+entry_stub!(overflow(_frame: InterruptStackFrame), cs = 8);
+entry_stub!(divide_error(_frame: InterruptStackFrame), cs = 8);
+entry_stub!(general_protection_fault(_frame: InterruptStackFrame, _code: u64), cs = 16);
+entry_stub!(page_fault(_frame: InterruptStackFrame, _code: PageFaultErrorCode), cs = 16);
+entry_stub!(double_fault(_frame: InterruptStackFrame, _code: u64) -> !, cs = 16);
+// Breakpoints from userspace never reach the handler (the gate is ring 0), so
+// it always returns to the kernel.
+entry_stub!(breakpoint(_frame: InterruptStackFrame), cs = 8);
@@ -4,9 +4,10 @@ use core::mem::MaybeUninit;
 use x86_64_impl::registers::control::Cr2;
 use x86_64_impl::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
 
-use super::{KEYBOARD_INT, PICS, TIMER_INT};
+use super::{PICS, TIMER_INT};
 use crate::arch::exec::{ControlRegs, PreservedRegs, Regs, SaveState, ScratchRegs};
-use crate::arch::x86_64::gdt;
+use crate::arch::paging::VirtAddr;
+use crate::arch::x86_64::{gdt, user};
 
 pub struct SyscallCtx {
     pub control_regs: ControlRegs,
@@ -61,12 +62,14 @@ impl SaveState for IrqCtx {
 }
 
 impl IrqCtx {
-    /// Reads the syscall context from the stack
+    /// Reads the context of the interrupted thread from the stack
     ///
     /// # Safety
     ///
-    /// Must be currently handling a syscall
+    /// Must be currently handling an interrupt from userspace
     pub unsafe fn current() -> Self {
+        // The CPU pushes 5 words, then the handler pushes the preserved and
+        // the scratch registers.
         let stack_end: *mut u64 = gdt::interrupt_stack_end().as_mut_ptr();
         let rsp = unsafe { *stack_end.sub(2) };
         let rflags = unsafe { *stack_end.sub(3) };
@@ -74,7 +77,7 @@ impl IrqCtx {
         let mut preserved: MaybeUninit<PreservedRegs> = MaybeUninit::uninit();
         unsafe {
             core::ptr::copy_nonoverlapping(
-                stack_end.sub(12) as *const PreservedRegs,
+                stack_end.sub(11) as *const PreservedRegs,
                 preserved.as_mut_ptr(),
                 1,
             );
@@ -82,7 +85,7 @@ impl IrqCtx {
         let mut scratch: MaybeUninit<ScratchRegs> = MaybeUninit::uninit();
         unsafe {
             core::ptr::copy_nonoverlapping(
-                stack_end.sub(21) as *const ScratchRegs,
+                stack_end.sub(20) as *const ScratchRegs,
                 scratch.as_mut_ptr(),
                 1,
             );
@@ -158,6 +161,7 @@ macro_rules! pop_preserved {
 macro_rules! interrupt {
     ($name:ident, $handler:expr) => {
         #[naked]
+        #[link_section = ".text.entry"]
         pub(super) extern "x86-interrupt" fn $name(_frame: InterruptStackFrame) {
             extern "C" fn inner() {
                 #[allow(clippy::redundant_closure_call)]
@@ -166,11 +170,13 @@ macro_rules! interrupt {
             // SAFETY: Following ABI with iretq and we only wrap a C call with push/pop scratch registers.
             unsafe {
                 core::arch::asm!(
+                    switch_to_kernel_tables!(8),
                     push_preserved!(),
                     push_scratch!(),
                     "call {inner}",
                     pop_scratch!(),
                     pop_preserved!(),
+                    switch_to_user_tables!(8),
                     "iretq",
                     inner = sym inner,
                     options(noreturn),
@@ -187,24 +193,51 @@ interrupt!(timer_interrupt, || {
     }
 });
 
-interrupt!(keyboard_interrupt, || {
-    // SAFETY: Notify keyboard interrupt vector.
-    unsafe {
-        PICS.notify_end_of_interrupt(KEYBOARD_INT);
-    }
+macro_rules! irq_interrupts {
+    ($($name:ident = $line:literal),*) => {
+        $(interrupt!($name, || super::irq_fired($line));)*
+    };
+}
+
+irq_interrupts!(
+    irq1 = 1,
+    irq3 = 3,
+    irq4 = 4,
+    irq5 = 5,
+    irq6 = 6,
+    irq7 = 7,
+    irq8 = 8,
+    irq9 = 9,
+    irq10 = 10,
+    irq11 = 11,
+    irq12 = 12,
+    irq13 = 13,
+    irq14 = 14,
+    irq15 = 15
+);
+
+interrupt!(lapic_timer_interrupt, || {
+    crate::arch::apic::timer_expired();
+});
+
+interrupt!(spurious_interrupt, || {
+    // Spurious interrupts must not be acknowledged.
 });
 
 #[naked]
+#[link_section = ".text.entry"]
 pub(super) extern "x86-interrupt" fn syscall_interrupt(stack_frame: InterruptStackFrame) {
     // SAFETY: Very thin wrapper over a syscall. We don't need to do callee saved since sysv64 abi will
     // take care of that.
     unsafe {
         asm!(
+            switch_to_kernel_tables!(8),
             push_preserved!(),
             "sub rsp, 8",
             "call {handle_syscall}",
             "add rsp, 8",
             pop_preserved!(),
+            switch_to_user_tables!(8),
             "iretq",
             handle_syscall = sym crate::syscall::handle,
             options(noreturn));
@@ -300,9 +333,20 @@ pub(super) extern "x86-interrupt" fn general_protection_fault(
 }
 
 pub(super) extern "x86-interrupt" fn page_fault(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    let rip = VirtAddr::new(stack_frame.instruction_pointer.as_u64() as usize);
+    if let Some(fixup) = user::fixup(rip) {
+        // SAFETY: Resumes the interrupted copy right after the faulting
+        // instruction, which it's ready for.
+        unsafe {
+            stack_frame.as_mut().update(|frame| {
+                frame.instruction_pointer = x86_64_impl::VirtAddr::new(fixup.as_usize() as u64);
+            });
+        }
+        return;
+    }
     let addrs: *const () = Cr2::read().unwrap().as_ptr();
     panic!("EXCEPTION: PAGE FAULT @ {addrs:#?} - {error_code:#02X}\n{stack_frame:#?}");
 }
@@ -0,0 +1,209 @@
+//! Userspace access to I/O ports.
+//!
+//! Ring 3 can only use `in` and `out` on the ports cleared in the TSS I/O
+//! permission bitmap. The bitmap holds the ports of the running thread, and is
+//! rewritten when a thread with other ports is dispatched. The ports the kernel
+//! drives itself are never cleared.
+
+use core::ops::Range;
+use core::sync::atomic::Ordering;
+
+use sync::cell::AtomicRefCell;
+
+use super::gdt;
+
+/// Maximum number of port ranges granted to a thread.
+pub const MAX_PORT_RANGES: usize = 4;
+
+/// Ports the kernel drives: the PICs, the PIT and its speaker gate, COM1, and
+/// the PCI configuration ports.
+const KERNEL_PORTS: [Range<u32>; 6] = [
+    0x20..0x22,
+    0x40..0x44,
+    0x61..0x62,
+    0xA0..0xA2,
+    0x3F8..0x400,
+    0xCF8..0xD00,
+];
+
+/// Ports loaded in the bitmap.
+// FIXME: This needs to be per-core, like the TSS.
+static LOADED: AtomicRefCell<PortSet> = AtomicRefCell::new(PortSet::new());
+
+/// A contiguous range of I/O ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    base: u16,
+    /// Offset of the last port, so that the range fits in a capability next
+    /// to its grantee even when it covers the whole port space.
+    last: u16,
+}
+
+impl PortRange {
+    pub fn new(base: u16, count: u32) -> Option<Self> {
+        if count == 0 || base as u32 + count > 1 << 16 {
+            return None;
+        }
+        Some(Self {
+            base,
+            last: (count - 1) as u16,
+        })
+    }
+
+    /// The range covering every port.
+    pub const fn all() -> Self {
+        Self {
+            base: 0,
+            last: u16::MAX,
+        }
+    }
+
+    /// Number of ports, up to the whole port space.
+    fn count(&self) -> u32 {
+        self.last as u32 + 1
+    }
+
+    /// Splits the range into its first `offset` ports and the rest.
+    pub fn split(&self, offset: u32) -> Option<(Self, Self)> {
+        if offset == 0 || offset >= self.count() {
+            return None;
+        }
+        let lower = Self {
+            base: self.base,
+            last: (offset - 1) as u16,
+        };
+        let upper = Self {
+            base: self.base + offset as u16,
+            last: self.last - offset as u16,
+        };
+        Some((lower, upper))
+    }
+
+    fn ports(&self) -> Range<u32> {
+        self.base as u32..self.base as u32 + self.count()
+    }
+}
+
+/// The port ranges granted to a thread.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PortSet([Option<PortRange>; MAX_PORT_RANGES]);
+
+impl PortSet {
+    pub const fn new() -> Self {
+        Self([None; MAX_PORT_RANGES])
+    }
+
+    /// Adds `range` to the set, returning false if the set is full.
+    ///
+    /// Every grant takes its own entry, even if the range is already in the
+    /// set, so that revoking one grant leaves the others in place.
+    pub fn insert(&mut self, range: PortRange) -> bool {
+        match self.0.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(range);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes one entry of `range` from the set, returning false if it
+    /// wasn't in it.
+    pub fn remove(&mut self, range: PortRange) -> bool {
+        match self.0.iter_mut().find(|slot| **slot == Some(range)) {
+            Some(slot) => {
+                *slot = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn ranges(&self) -> impl Iterator<Item = Range<u32>> + '_ {
+        self.0.iter().flatten().map(PortRange::ports)
+    }
+}
+
+/// Loads the ports of the thread about to be dispatched into the bitmap.
+pub fn load(ports: PortSet) {
+    let mut loaded = LOADED.borrow_mut().expect("Ports loaded concurrently");
+    let previous = core::mem::replace(&mut *loaded, ports);
+    if previous == ports {
+        return;
+    }
+    for range in previous.ranges() {
+        set_access(range, false);
+    }
+    for range in ports.ranges() {
+        set_access(range, true);
+    }
+    for range in KERNEL_PORTS {
+        set_access(range, false);
+    }
+}
+
+fn set_access(ports: Range<u32>, allowed: bool) {
+    let bitmap = gdt::io_bitmap();
+    for port in ports {
+        let bit = 1 << (port % 8);
+        let byte = &bitmap[port as usize / 8];
+        if allowed {
+            byte.fetch_and(!bit, Ordering::Relaxed);
+        } else {
+            byte.fetch_or(bit, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn kernel_ports_are_never_granted() {
+        let mut ports = PortSet::new();
+        assert!(ports.insert(PortRange::all()));
+        load(ports);
+        let bitmap = gdt::io_bitmap();
+        let allowed =
+            |port: u32| bitmap[port as usize / 8].load(Ordering::Relaxed) & (1 << (port % 8)) == 0;
+        assert!(allowed(0x60));
+        assert!(!allowed(0x20));
+        assert!(!allowed(0x3F8));
+        load(PortSet::new());
+        assert!(!allowed(0x60));
+    }
+
+    #[test_case]
+    fn grants_are_removed_one_at_a_time() {
+        let range = PortRange::new(0x60, 8).unwrap();
+        let overlapping = PortRange::new(0x64, 8).unwrap();
+        let mut ports = PortSet::new();
+        assert!(ports.insert(range));
+        assert!(ports.insert(range));
+        assert!(ports.insert(overlapping));
+        assert!(ports.remove(range));
+        assert_eq!(ports.ranges().count(), 2);
+        assert!(ports.remove(range));
+        assert!(!ports.remove(range));
+        let mut ranges = ports.ranges();
+        assert_eq!(ranges.next(), Some(0x64..0x6C));
+        assert_eq!(ranges.next(), None);
+    }
+
+    #[test_case]
+    fn pci_config_ports_are_never_granted() {
+        let mut ports = PortSet::new();
+        assert!(ports.insert(PortRange::new(0xCF0, 0x20).unwrap()));
+        load(ports);
+        let bitmap = gdt::io_bitmap();
+        let allowed =
+            |port: u32| bitmap[port as usize / 8].load(Ordering::Relaxed) & (1 << (port % 8)) == 0;
+        assert!(allowed(0xCF0));
+        assert!(!allowed(0xCF8));
+        assert!(!allowed(0xCFC));
+        assert!(!allowed(0xCFF));
+        assert!(allowed(0xD00));
+        load(PortSet::new());
+    }
+}
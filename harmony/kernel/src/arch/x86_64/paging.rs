@@ -2,15 +2,27 @@ pub const PAGE_SIZE: usize = 4096;
 pub const FRAME_SIZE: u64 = 4096;
 
 pub mod frames;
+pub mod kernel_image;
 pub use frames::RawFrame;
 
 pub mod pages;
 pub use pages::Page;
 
+#[cfg(feature = "KPTI")]
+pub mod kpti;
+pub mod mmio;
 pub mod page_table;
+pub mod pat;
+pub mod tlb;
 
 mod physical_address;
 pub use physical_address::PhysAddr;
 
 mod virtual_address;
 pub use virtual_address::VirtAddr;
+
+/// Returns the number of physical address bits supported by the CPU.
+pub fn physical_address_bits() -> u32 {
+    // SAFETY: CPUID is always available in long mode.
+    unsafe { core::arch::x86_64::__cpuid(0x8000_0008) }.eax & 0xFF
+}
@@ -0,0 +1,64 @@
+//! Permissions of the kernel image mappings.
+//!
+//! The bootloader maps the whole image, so the kernel tightens the
+//! permissions of each section itself: text is read-only and executable,
+//! rodata is read-only and data (including bss) is writable but not
+//! executable. No page of the image is ever both writable and executable.
+
+use core::ptr::addr_of;
+
+use super::page_table::{AnyPageTable, PageTableFlags};
+use super::{Page, VirtAddr, PAGE_SIZE};
+
+extern "C" {
+    static __text_start: u8;
+    static __rodata_start: u8;
+    static __data_start: u8;
+    static __kernel_end: u8;
+}
+
+/// Remaps the kernel image with W^X permissions.
+///
+/// Must run before any user address space is created, although the image is
+/// mapped by tables shared with every address space.
+pub fn enforce_wx() {
+    // SAFETY: The linker script defines these symbols at the section
+    // boundaries.
+    let (text, rodata, data, end) = unsafe {
+        (
+            addr_of!(__text_start) as usize,
+            addr_of!(__rodata_start) as usize & !(PAGE_SIZE - 1),
+            addr_of!(__data_start) as usize & !(PAGE_SIZE - 1),
+            addr_of!(__kernel_end) as usize,
+        )
+    };
+    let table = AnyPageTable::current();
+    // SAFETY: The current table is a root table.
+    let addrspace = unsafe { table.as_addrspace() };
+    let mut pages = 0;
+    for addr in (text..end).step_by(PAGE_SIZE) {
+        let page = Page::containing_address(VirtAddr::new(addr));
+        let Some((_frame, flags, level)) = addrspace.get(page) else {
+            // Padding between the sections.
+            continue;
+        };
+        if !level.is_bottom() {
+            log::warn!("Kernel image mapped with huge pages, leaving {page:?} as is");
+            continue;
+        }
+        let mut new = flags - PageTableFlags::WRITABLE - PageTableFlags::NO_EXECUTE;
+        if addr >= data {
+            new |= PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        } else if addr >= rodata {
+            new |= PageTableFlags::NO_EXECUTE;
+        }
+        if new != flags {
+            // SAFETY: The permissions removed are never relied on.
+            unsafe {
+                addrspace.set_flags(page, new);
+            }
+        }
+        pages += 1;
+    }
+    log::info!("Remapped {pages} kernel image pages as W^X");
+}
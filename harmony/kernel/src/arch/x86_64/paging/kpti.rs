@@ -0,0 +1,184 @@
+//! Kernel page-table isolation.
+//!
+//! Userspace runs on a separate top-level table, the user view, whose lower
+//! half mirrors the address space being run and whose upper half only maps
+//! what the CPU needs to enter the kernel: the entry code (`.text.entry`), the
+//! descriptor tables and interrupt stacks (`.data.entry`) and the shared time
+//! page. The entry stubs switch to the full kernel tables (the address space's
+//! own root table) before running anything else, and back before returning.
+//!
+//! Each core has a single user view, refreshed whenever the kernel returns to
+//! userspace. When PCIDs are enabled the view is tagged with the PCID of the
+//! address space plus [`USER_PCID`], so its TLB entries never leak into the
+//! kernel's and the other way around.
+
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kapi::time::TIME_PAGE_ADDRESS;
+use sync::cell::AtomicOnceCell;
+
+use super::page_table::{AnyPageTable, PageTableFlags, PageTableLevel, PageTableOffset};
+use super::tlb::{self, CR3_NO_FLUSH};
+use super::{Page, RawFrame, VirtAddr, PAGE_SIZE};
+use crate::bump_allocator::BumpAllocator;
+use crate::core_local::CoreLocal;
+
+/// Added to the PCID of an address space to tag its user view.
+const USER_PCID: u64 = 1 << 11;
+
+extern "C" {
+    static __entry_text_start: u8;
+    static __entry_text_end: u8;
+    static __entry_data_start: u8;
+    static __entry_data_end: u8;
+}
+
+// CR3 values loaded by the entry stubs. They live with the entry data since
+// they're read before (and after) switching tables.
+// FIXME: These need to be per-core.
+#[no_mangle]
+#[link_section = ".data.entry"]
+static KPTI_KERNEL_CR3: AtomicU64 = AtomicU64::new(0);
+#[no_mangle]
+#[link_section = ".data.entry"]
+static KPTI_USER_CR3: AtomicU64 = AtomicU64::new(0);
+
+static USER_VIEW: AtomicOnceCell<CoreLocal<RawFrame>> = AtomicOnceCell::new();
+
+/// Builds the user view of each core.
+///
+/// Must run after the time page is mapped and before the first dispatch.
+pub fn init() {
+    let view = CoreLocal::new_with(|_| build_user_view());
+    if USER_VIEW.set(view).is_err() {
+        panic!("KPTI initialized twice");
+    }
+    log::info!("Enabled kernel page-table isolation");
+}
+
+fn build_user_view() -> RawFrame {
+    let frame = BumpAllocator::new()
+        .alloc_kernel_frame()
+        .expect("Couldn't allocate the KPTI user view")
+        .into_raw();
+    // SAFETY: The frame was just retyped (and zeroed), an empty table.
+    let view: &AnyPageTable = unsafe { &*frame.base().to_virtual().as_ptr() };
+    let kernel = AnyPageTable::current();
+
+    // SAFETY: The linker script defines these symbols around the entry
+    // sections, which are page aligned.
+    let (text, data) = unsafe {
+        (
+            addr_of!(__entry_text_start) as usize..addr_of!(__entry_text_end) as usize,
+            addr_of!(__entry_data_start) as usize..addr_of!(__entry_data_end) as usize,
+        )
+    };
+    let sections = [
+        (text, PageTableFlags::PRESENT),
+        (
+            data,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        ),
+    ];
+    for (range, flags) in sections {
+        for addr in range.step_by(PAGE_SIZE) {
+            let page = Page::from_start_address(VirtAddr::new(addr));
+            // SAFETY: The current table is a root table.
+            let (frame, _flags, _level) = unsafe { kernel.as_addrspace() }
+                .get(page)
+                .expect("Entry sections must be mapped");
+            // SAFETY: The view is a fresh root table, and the pages are
+            // mapped to the same frames as in the kernel's tables.
+            unsafe {
+                view.as_addrspace()
+                    .map_to(
+                        page,
+                        frame,
+                        flags,
+                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                        &mut BumpAllocator::new(),
+                    )
+                    .expect("Couldn't map the entry sections");
+            }
+        }
+    }
+
+    // The time page is the only mapping under its top-level entry.
+    let offset = VirtAddr::new(TIME_PAGE_ADDRESS).page_table_index(PageTableLevel::top());
+    if let Some((table, flags)) = kernel.get(offset).get() {
+        // SAFETY: The entry only grants access to the time page.
+        unsafe {
+            view.map(offset, table, flags);
+        }
+    }
+    frame
+}
+
+fn user_view_frame() -> RawFrame {
+    *USER_VIEW.get().expect("KPTI is not initialized").get()
+}
+
+fn user_view() -> &'static AnyPageTable {
+    // SAFETY: The user view is a kernel frame that's never released.
+    unsafe { &*user_view_frame().base().to_virtual().as_ptr() }
+}
+
+/// Mirrors the lower half of the address space rooted at `l4_frame` in the
+/// user view.
+fn mirror(l4_frame: RawFrame) {
+    // SAFETY: Root tables are accessed through the direct mapping.
+    let table: &AnyPageTable = unsafe { &*l4_frame.base().to_virtual().as_ptr() };
+    let view = user_view();
+    for i in 0..256 {
+        let offset = PageTableOffset::new(i).unwrap();
+        // SAFETY: The user view gets the exact same user mappings as the
+        // address space itself.
+        unsafe {
+            match table.get(offset).get() {
+                Some((frame, flags)) => view.get(offset).set(frame, flags),
+                None => view.get(offset).reset(),
+            };
+        }
+    }
+}
+
+/// Prepares the switch to the address space rooted at `l4_frame`, returning
+/// the CR3 value of its user view.
+///
+/// The entry stubs will switch back to the address space itself.
+pub fn prepare_dispatch(l4_frame: RawFrame) -> u64 {
+    mirror(l4_frame);
+    let address = l4_frame.base().as_u64();
+    let view = user_view_frame().base().as_u64();
+    let (kernel_cr3, user_cr3) = match tlb::pcid_for(l4_frame) {
+        Some((pcid, false)) => (
+            address | pcid | CR3_NO_FLUSH,
+            view | pcid | USER_PCID | CR3_NO_FLUSH,
+        ),
+        Some((pcid, true)) => {
+            // Drop what the previous owner of the PCID left behind in the
+            // kernel's tables right away, the entry stubs never flush.
+            // SAFETY: The kernel half is the same in every address space.
+            unsafe {
+                core::arch::asm!("mov cr3, {}", in(reg) address | pcid, options(nostack));
+            }
+            (address | pcid | CR3_NO_FLUSH, view | pcid | USER_PCID)
+        }
+        None => (address, view),
+    };
+    KPTI_KERNEL_CR3.store(kernel_cr3, Ordering::Relaxed);
+    KPTI_USER_CR3.store(user_cr3, Ordering::Relaxed);
+    user_cr3
+}
+
+/// Refreshes the user view before returning to the current address space,
+/// whose top-level entries may have changed.
+pub fn sync_user_view() {
+    mirror(AnyPageTable::current_raw());
+}
+
+/// Makes the next return to userspace flush the TLB entries of the user view.
+pub fn flush_user_view() {
+    KPTI_USER_CR3.fetch_and(!CR3_NO_FLUSH, Ordering::Relaxed);
+}
@@ -0,0 +1,60 @@
+//! Kernel mappings for memory-mapped devices.
+//!
+//! Device registers aren't guaranteed to be covered by the higher-half direct
+//! mapping, so the kernel maps them into a dedicated window in the top half.
+//! These mappings are created during boot, before any user address space
+//! clones the kernel entries, and they are never removed. Their frames are
+//! taken out of the device memory userspace can map.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::page_table::{AnyPageTable, MapperError, PageTableFlags};
+use super::{pat, Page, PhysAddr, RawFrame, VirtAddr, FRAME_SIZE, PAGE_SIZE};
+use crate::bump_allocator::BumpAllocator;
+
+const MMIO_START: usize = 0xFFFF_A000_0000_0000;
+const MMIO_END: usize = 0xFFFF_B000_0000_0000;
+
+static NEXT: AtomicUsize = AtomicUsize::new(MMIO_START);
+
+/// Maps `size` bytes of device memory starting at `base` as uncacheable.
+///
+/// Returns the virtual address corresponding to `base`.
+pub fn map(base: PhysAddr, size: usize) -> Result<VirtAddr, MapperError> {
+    let offset = (base.as_u64() % FRAME_SIZE) as usize;
+    let pages = (offset + size).div_ceil(PAGE_SIZE);
+    let start = NEXT.fetch_add(pages * PAGE_SIZE, Ordering::Relaxed);
+    assert!(
+        start + pages * PAGE_SIZE <= MMIO_END,
+        "Ran out of kernel MMIO space"
+    );
+
+    let table = AnyPageTable::current();
+    // SAFETY: The current table is the kernel's root table.
+    let addrspace = unsafe { table.as_addrspace() };
+    let mut fallocator = BumpAllocator::new();
+    let first_frame = base.as_u64() - offset as u64;
+    for i in 0..pages {
+        let frame =
+            RawFrame::from_start_address(PhysAddr::new(first_frame + i as u64 * FRAME_SIZE));
+        let page = Page::from_start_address(VirtAddr::new(start + i * PAGE_SIZE));
+        // Keeps userspace from mapping the kernel's devices. Frames the memory
+        // map reserved are already unavailable.
+        let _ = frame.reserve_device();
+        // SAFETY: The window is reserved for device memory and each page is
+        // only handed out once.
+        unsafe {
+            addrspace.map_to(
+                page,
+                frame,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | pat::UNCACHEABLE
+                    | PageTableFlags::NO_EXECUTE,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                &mut fallocator,
+            )?;
+        }
+    }
+    Ok(VirtAddr::new(start + offset))
+}
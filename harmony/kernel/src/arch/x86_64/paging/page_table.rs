@@ -1,9 +1,10 @@
+use core::ops::Range;
 use core::sync::atomic::{AtomicU64, Ordering};
 
 use x86_64_impl::registers::control::Cr3;
 pub use x86_64_impl::structures::paging::PageTableFlags;
 
-use super::{Page, PhysAddr, RawFrame};
+use super::{tlb, Page, PhysAddr, RawFrame, VirtAddr};
 use crate::bump_allocator::BumpAllocator;
 use crate::kptr::KPtr;
 use crate::retyping::RetypeError;
@@ -39,8 +40,99 @@ impl<'a> Addrspace<'a> {
     }
 
     /// Recursively finds the mapping for a page to a frame.
-    pub fn get(&self, _page: Page) -> Option<(RawFrame, PageTableFlags)> {
-        todo!();
+    ///
+    /// Returns the frame backing the page along with the flags and level of
+    /// the entry mapping it. If the page is part of a huge page, the frame is
+    /// the 4KiB frame within the huge frame that backs the page.
+    pub fn get(&self, page: Page) -> Option<(RawFrame, PageTableFlags, PageTableLevel)> {
+        let mapping = self.walk(page.base()).ok()?;
+        let offset = page.base().as_usize() - mapping.start.as_usize();
+        let frame = RawFrame::from_start_address(PhysAddr::new(
+            mapping.frame.base().as_u64() + offset as u64,
+        ));
+        Some((frame, mapping.flags, mapping.level))
+    }
+
+    /// Returns an iterator over the mappings intersecting the range.
+    ///
+    /// Each mapping is yielded once, even when it only partially overlaps the
+    /// range.
+    pub fn mappings(&self, range: Range<VirtAddr>) -> Mappings<'_> {
+        Mappings {
+            addrspace: self,
+            next: Some(range.start.as_usize()),
+            end: range.end.as_usize(),
+        }
+    }
+
+    /// Replaces the flags of the leaf entry mapping the page, flushing it
+    /// from the TLBs.
+    ///
+    /// Returns the previous flags and the level of the entry.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may rely on the permissions being removed.
+    pub unsafe fn set_flags(
+        &self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Option<(PageTableFlags, PageTableLevel)> {
+        let mut level = PageTableLevel::top();
+        let mut table = self.0;
+        let addr = page.base();
+        loop {
+            let entry = table.get(addr.page_table_index(level));
+            let (frame, old) = entry.get()?;
+            if level.is_bottom() || old.contains(PageTableFlags::HUGE_PAGE) {
+                let old = unsafe { entry.set_flags(flags) };
+                tlb::flush_page(self.l4_frame(), addr);
+                return Some((old, level));
+            }
+            if !old.contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            // SAFETY: Non-leaf entries point to page tables.
+            table = unsafe { &*frame.base().to_virtual().as_ptr() };
+            level = level.lower().unwrap();
+        }
+    }
+
+    fn l4_frame(&self) -> RawFrame {
+        let table = VirtAddr::from_ptr(self.0 as *const AnyPageTable);
+        // SAFETY: Page tables are accessed through the direct mapping.
+        RawFrame::from_start_address(unsafe { PhysAddr::from_virtual(table) })
+    }
+
+    /// Walks the page tables down to the leaf entry mapping the address.
+    ///
+    /// Fails with the level at which there was no entry to follow.
+    fn walk(&self, addr: VirtAddr) -> Result<Mapping, PageTableLevel> {
+        let mut level = PageTableLevel::top();
+        let mut table = self.0;
+        loop {
+            let entry = table.get(addr.page_table_index(level));
+            let Some((frame, flags)) = entry.get() else {
+                return Err(level);
+            };
+            if level.is_bottom() || flags.contains(PageTableFlags::HUGE_PAGE) {
+                let size = level.page_size();
+                // The PAT bit on huge entries overlaps the frame address.
+                let frame = frame.base().as_u64() & !(size as u64 - 1);
+                return Ok(Mapping {
+                    start: VirtAddr::new(addr.as_usize() & !(size - 1)),
+                    frame: RawFrame::from_start_address(PhysAddr::new(frame)),
+                    flags,
+                    level,
+                });
+            }
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return Err(level);
+            }
+            // SAFETY: Non-leaf entries point to page tables.
+            table = unsafe { &*frame.base().to_virtual().as_ptr() };
+            level = level.lower().unwrap();
+        }
     }
 
     /// Maps a virtual page to a physical frame.
@@ -57,40 +149,136 @@ impl<'a> Addrspace<'a> {
         parent_flags: PageTableFlags,
         frame_allocator: &mut BumpAllocator,
     ) -> Result<(), MapperError> {
-        let mut level = Some(PageTableLevel::top());
+        unsafe {
+            self.map_to_level(
+                page,
+                frame,
+                PageTableLevel::new(1),
+                flags,
+                parent_flags,
+                frame_allocator,
+            )
+        }
+    }
+
+    /// Maps the region starting at the page to the frame with an entry at
+    /// `level`.
+    ///
+    /// Entries above level 1 map huge pages (2MiB at level 2 and 1GiB at level
+    /// 3), in which case both the page and the frame must be aligned to the
+    /// huge page size.
+    ///
+    /// # Safety
+    ///
+    /// Creating virtual memory mappings is a fundamentally unsafe operation as it enables
+    /// aliasing (shared memory).
+    pub unsafe fn map_to_level(
+        &self,
+        page: Page,
+        frame: RawFrame,
+        level: PageTableLevel,
+        mut flags: PageTableFlags,
+        parent_flags: PageTableFlags,
+        frame_allocator: &mut BumpAllocator,
+    ) -> Result<(), MapperError> {
+        assert!(level.level() < 4, "Can't map L4 entries to frames");
+        assert!(page.base().as_usize() % level.page_size() == 0);
+        assert!(frame.base().as_u64() % level.page_size() as u64 == 0);
+        if !level.is_bottom() {
+            flags |= PageTableFlags::HUGE_PAGE;
+        }
+        let mut current_level = PageTableLevel::top();
         let mut table = self.0;
         let addr = page.base();
-        while let Some(current_level) = level {
-            level = current_level.lower();
+        loop {
             let offset = addr.page_table_index(current_level);
             let entry = table.get(offset);
+            if current_level.level() == level.level() {
+                // SAFETY: Caller guarantees the mapping is sound.
+                return unsafe { entry.try_set(frame, flags) }
+                    .map_err(|(frame, _flags)| MapperError::AlreadyMapped(frame));
+            }
             match entry.get() {
                 Some((frame, flags)) => {
-                    if current_level.level() == 1 {
-                        return Err(MapperError::AlreadyMapped(frame));
-                    }
                     if flags.contains(PageTableFlags::HUGE_PAGE) {
                         return Err(MapperError::HugeParentEntry);
                     }
                     table = unsafe { &*frame.base().to_virtual().as_ptr() };
                 }
                 None => {
-                    if current_level.is_bottom() {
-                        entry.set(frame, flags);
-                    } else {
-                        let frame = frame_allocator
-                            .alloc_kernel_frame()
-                            .ok_or(MapperError::FrameAllocationError)?
-                            .into_raw();
-                        let addr: *mut AnyPageTable = frame.base().to_virtual().as_mut_ptr();
-                        addr.write(AnyPageTable::new());
-                        table = unsafe { &*addr };
-                        entry.set(frame, parent_flags | PageTableFlags::PRESENT);
-                    }
+                    let frame = frame_allocator
+                        .alloc_kernel_frame()
+                        .ok_or(MapperError::FrameAllocationError)?
+                        .into_raw();
+                    let addr: *mut AnyPageTable = frame.base().to_virtual().as_mut_ptr();
+                    addr.write(AnyPageTable::new());
+                    table = unsafe { &*addr };
+                    entry.set(frame, parent_flags | PageTableFlags::PRESENT);
+                }
+            }
+            current_level = current_level.lower().unwrap();
+        }
+    }
+}
+
+/// A leaf entry in an address space.
+#[derive(Debug, Copy, Clone)]
+pub struct Mapping {
+    /// First virtual address covered by the entry.
+    pub start: VirtAddr,
+    /// First frame covered by the entry.
+    pub frame: RawFrame,
+    pub flags: PageTableFlags,
+    /// Level of the entry, where anything above 1 is a huge page.
+    pub level: PageTableLevel,
+}
+
+impl Mapping {
+    /// Number of bytes covered by the mapping.
+    pub fn size(&self) -> usize {
+        self.level.page_size()
+    }
+}
+
+/// Iterator over the mappings in a range of an address space.
+pub struct Mappings<'a> {
+    addrspace: &'a Addrspace<'a>,
+    next: Option<usize>,
+    end: usize,
+}
+
+impl Mappings<'_> {
+    /// Start of the non-canonical hole between the lower and upper halves.
+    const HOLE_START: usize = 0x0000_8000_0000_0000;
+    /// Start of the upper half.
+    const HOLE_END: usize = 0xFFFF_8000_0000_0000;
+
+    /// Moves past the region of `size` bytes containing `addr`.
+    fn skip(&mut self, addr: usize, size: usize) {
+        self.next = (addr & !(size - 1)).checked_add(size).map(|next| {
+            if (Self::HOLE_START..Self::HOLE_END).contains(&next) {
+                Self::HOLE_END
+            } else {
+                next
+            }
+        });
+    }
+}
+
+impl Iterator for Mappings<'_> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let addr = self.next.filter(|&addr| addr < self.end)?;
+            match self.addrspace.walk(VirtAddr::new(addr)) {
+                Ok(mapping) => {
+                    self.skip(addr, mapping.size());
+                    return Some(mapping);
                 }
+                Err(level) => self.skip(addr, level.page_size()),
             }
         }
-        Ok(())
     }
 }
 
@@ -129,7 +317,9 @@ impl AnyPageTable {
     }
 
     pub fn new_l4(frame: RawFrame) -> Result<KPtr<Self>, RetypeError> {
-        KPtr::new(frame, AnyPageTable::clone_kernel())
+        let table = KPtr::new(frame, AnyPageTable::clone_kernel())?;
+        tlb::forget_address_space(frame);
+        Ok(table)
     }
 
     pub fn clone_kernel() -> Self {
@@ -148,6 +338,27 @@ impl AnyPageTable {
         new
     }
 
+    /// Calls `fun` with the frame of every table below this one, which sits at
+    /// `level`.
+    pub fn for_each_table<F: FnMut(RawFrame)>(&self, level: PageTableLevel, fun: &mut F) {
+        let Some(lower) = level.lower() else {
+            return;
+        };
+        for entry in self.0.iter() {
+            let Some((frame, flags)) = entry.get() else {
+                continue;
+            };
+            if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                continue;
+            }
+            fun(frame);
+            // SAFETY: Non-leaf entries point to page tables.
+            let table: &Self = unsafe { &*frame.base().to_virtual().as_ptr() };
+            table.for_each_table(lower, fun);
+        }
+    }
+
     pub fn get(&self, offset: PageTableOffset) -> &PageTableEntry {
         // SAFETY: Offset is within [0, 512)
         unsafe { self.0.get_unchecked(offset.0 as usize) }
@@ -260,6 +471,30 @@ impl PageTableEntry {
         unsafe { self.set_bits(attributes.bits() | frame.base().as_u64()) }
     }
 
+    /// Atomically sets this entry to the frame and the attributes if it's empty.
+    ///
+    /// Returns the current contents of the entry otherwise.
+    ///
+    /// # Safety
+    ///
+    /// This could fundamentally change memory, leading to unsoundness.
+    pub unsafe fn try_set(
+        &self,
+        frame: RawFrame,
+        attributes: PageTableFlags,
+    ) -> Result<(), (RawFrame, PageTableFlags)> {
+        let bits = attributes.bits() | frame.base().as_u64();
+        self.0
+            .compare_exchange(0, bits, Ordering::Relaxed, Ordering::Relaxed)
+            .map(|_| ())
+            .map_err(|old| {
+                (
+                    RawFrame::from_start_address(PhysAddr::new(old & Self::FRAME_MASK)),
+                    PageTableFlags::from_bits(old & Self::FLAGS_MASK).unwrap(),
+                )
+            })
+    }
+
     /// Atomically unsets this entry, leaving it empty
     ///
     /// # Safety
@@ -318,6 +553,21 @@ impl PageTableLevel {
         Self(4)
     }
 
+    /// Whether the CPU supports mapping huge pages with entries at this level.
+    pub fn supports_huge_pages(&self) -> bool {
+        match self.level() {
+            2 => true,
+            // SAFETY: CPUID is always available in long mode.
+            3 => unsafe { core::arch::x86_64::__cpuid(0x8000_0001) }.edx & (1 << 26) != 0,
+            _ => false,
+        }
+    }
+
+    /// Number of bytes mapped by an entry at this level.
+    pub const fn page_size(&self) -> usize {
+        super::PAGE_SIZE << (9 * (self.level() as usize - 1))
+    }
+
     pub const fn is_bottom(&self) -> bool {
         self.level() == 1
     }
@@ -368,3 +618,50 @@ impl PageTableOffset {
         Self(addr % 512)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn walks_kernel_mappings() {
+        let table = AnyPageTable::current();
+        // SAFETY: The current table is a root table.
+        let addrspace = unsafe { table.as_addrspace() };
+        let code = VirtAddr::new(AnyPageTable::current_raw as usize);
+        let (_frame, flags, _level) = addrspace.get(Page::containing_address(code)).unwrap();
+        assert!(flags.contains(PageTableFlags::PRESENT));
+
+        let start = VirtAddr::new(code.as_usize() & !(PageTableLevel::new(2).page_size() - 1));
+        let end = VirtAddr::new(start.as_usize() + PageTableLevel::new(2).page_size());
+        assert!(addrspace
+            .mappings(start..end)
+            .any(
+                |mapping| (mapping.start.as_usize()..mapping.start.as_usize() + mapping.size())
+                    .contains(&code.as_usize())
+            ));
+    }
+
+    #[test_case]
+    fn kernel_image_is_wx() {
+        static DATA: u8 = 0;
+        static mut BSS: u8 = 0;
+        let table = AnyPageTable::current();
+        // SAFETY: The current table is a root table.
+        let addrspace = unsafe { table.as_addrspace() };
+        let flags = |addr: usize| {
+            let page = Page::containing_address(VirtAddr::new(addr));
+            addrspace.get(page).unwrap().1
+        };
+
+        let code = flags(AnyPageTable::current_raw as usize);
+        assert!(!code.contains(PageTableFlags::WRITABLE));
+        assert!(!code.contains(PageTableFlags::NO_EXECUTE));
+        let rodata = flags(&DATA as *const u8 as usize);
+        assert!(!rodata.contains(PageTableFlags::WRITABLE));
+        assert!(rodata.contains(PageTableFlags::NO_EXECUTE));
+        // SAFETY: Only the address is taken.
+        let bss = flags(unsafe { core::ptr::addr_of!(BSS) } as usize);
+        assert!(bss.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    }
+}
@@ -0,0 +1,53 @@
+//! Page attribute table.
+//!
+//! The PAT entries are selected by the PWT, PCD and PAT bits of a mapping.
+//! The kernel keeps the power-on defaults except for entry 1 (PWT alone),
+//! which it turns from write-through into write-combining.
+
+use x86_64_impl::registers::model_specific::Msr;
+
+use super::page_table::PageTableFlags;
+
+const IA32_PAT: u32 = 0x277;
+
+/// Memory types of the PAT entries, from entry 0 to 7.
+const PAT: [u64; 8] = [
+    0x06, // Write-back
+    0x01, // Write-combining
+    0x07, // Uncacheable minus
+    0x00, // Uncacheable
+    0x06, // Write-back
+    0x04, // Write-through
+    0x07, // Uncacheable minus
+    0x00, // Uncacheable
+];
+
+/// Flags of an uncacheable mapping (PAT entry 3).
+pub const UNCACHEABLE: PageTableFlags =
+    PageTableFlags::NO_CACHE.union(PageTableFlags::WRITE_THROUGH);
+/// Flags of a write-combining mapping (PAT entry 1).
+pub const WRITE_COMBINING: PageTableFlags = PageTableFlags::WRITE_THROUGH;
+/// Flags selecting the PAT entry of a mapping.
+pub const CACHING: PageTableFlags = UNCACHEABLE;
+
+/// Programs the PAT.
+///
+/// Must run before anything is mapped write-through.
+pub fn init() {
+    // SAFETY: CPUID is always available in long mode.
+    let supported = unsafe { core::arch::x86_64::__cpuid(1) }.edx & (1 << 16) != 0;
+    if !supported {
+        log::warn!("The PAT is not supported, write-combining mappings are write-through");
+        return;
+    }
+    let value = PAT
+        .iter()
+        .enumerate()
+        .fold(0, |pat, (i, &kind)| pat | kind << (i * 8));
+    // SAFETY: Nothing is mapped with PWT alone yet, so no mapping changes
+    // memory type.
+    unsafe {
+        Msr::new(IA32_PAT).write(value);
+    }
+    log::info!("Programmed the PAT");
+}
@@ -0,0 +1,166 @@
+//! TLB maintenance.
+//!
+//! Entries have to be flushed from every core's TLB before the frames they
+//! pointed to are released, or userspace could keep accessing memory that has
+//! been handed to someone else.
+//!
+//! When the CPU supports it, address spaces are tagged with PCIDs so that
+//! switching between them doesn't flush the TLB. Each core keeps a small cache
+//! of the address spaces it last ran. An address space that is evicted from the
+//! cache (or whose mappings changed while it wasn't loaded) gets its PCID
+//! flushed the next time it's loaded.
+//!
+//! With KPTI, the user view of an address space has its own PCID, which has
+//! to be flushed separately.
+
+use core::arch::asm;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use sync::cell::AtomicLazyCell;
+use x86_64_impl::instructions::tlb;
+use x86_64_impl::registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags};
+
+use super::page_table::AnyPageTable;
+use super::{RawFrame, VirtAddr};
+use crate::core_local::CoreLocal;
+
+/// Number of address spaces that keep their PCID on each core.
+const PCID_SLOTS: usize = 32;
+/// Keeps the TLB entries tagged with the PCID when loading CR3.
+pub(super) const CR3_NO_FLUSH: u64 = 1 << 63;
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+static PCID_CACHE: AtomicLazyCell<CoreLocal<RefCell<PcidCache>>> =
+    AtomicLazyCell::new(|| CoreLocal::new_with(|_| RefCell::new(PcidCache::new())));
+
+/// Address spaces that own a PCID on a core.
+///
+/// The PCID of the address space in slot `i` is `i + 1`, PCID 0 is left to the
+/// kernel's boot address space.
+struct PcidCache {
+    slots: [Option<RawFrame>; PCID_SLOTS],
+    next_victim: usize,
+}
+
+impl PcidCache {
+    const fn new() -> Self {
+        Self {
+            slots: [None; PCID_SLOTS],
+            next_victim: 0,
+        }
+    }
+
+    fn forget(&mut self, l4_frame: RawFrame) {
+        for slot in self.slots.iter_mut() {
+            if *slot == Some(l4_frame) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+/// Enables PCIDs on the current core if the CPU supports them.
+pub fn init() {
+    // SAFETY: CPUID is always available in long mode.
+    let supported = unsafe { core::arch::x86_64::__cpuid(1) }.ecx & (1 << 17) != 0;
+    if !supported {
+        log::info!("PCIDs are not supported, address space switches flush the TLB");
+        return;
+    }
+    // The low bits of CR3 must be clear when enabling them.
+    let (frame, _flags) = Cr3::read();
+    // SAFETY: Reloading the same address space.
+    unsafe {
+        Cr3::write(frame, Cr3Flags::empty());
+        Cr4::update(|cr4| cr4.insert(Cr4Flags::PCID));
+    }
+    PCID_ENABLED.store(true, Ordering::Relaxed);
+    log::info!("Enabled PCIDs");
+}
+
+/// Returns the CR3 value that switches to the address space rooted at
+/// `l4_frame` on the current core.
+///
+/// The TLB entries of the address space are kept if it still owns its PCID.
+pub fn cr3_for(l4_frame: RawFrame) -> u64 {
+    let address = l4_frame.base().as_u64();
+    match pcid_for(l4_frame) {
+        Some((pcid, false)) => address | pcid | CR3_NO_FLUSH,
+        // Loading without the no-flush bit drops whatever the previous owner
+        // of the PCID left behind.
+        Some((pcid, true)) => address | pcid,
+        None => address,
+    }
+}
+
+/// Returns the PCID of the address space rooted at `l4_frame` on the current
+/// core, and whether it was just assigned (and must be flushed on load).
+///
+/// Returns `None` when PCIDs are disabled.
+pub(super) fn pcid_for(l4_frame: RawFrame) -> Option<(u64, bool)> {
+    if !PCID_ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let mut cache = PCID_CACHE.get().get().borrow_mut();
+    if let Some(slot) = cache.slots.iter().position(|slot| *slot == Some(l4_frame)) {
+        return Some((slot as u64 + 1, false));
+    }
+    let slot = cache.next_victim;
+    cache.next_victim = (slot + 1) % PCID_SLOTS;
+    cache.slots[slot] = Some(l4_frame);
+    Some((slot as u64 + 1, true))
+}
+
+/// Drops the PCID of the address space rooted at `l4_frame`, so its TLB
+/// entries are flushed the next time it's loaded.
+///
+/// Must be called before a frame is used as a new top-level table.
+pub fn forget_address_space(l4_frame: RawFrame) {
+    if PCID_ENABLED.load(Ordering::Relaxed) {
+        PCID_CACHE.get().get().borrow_mut().forget(l4_frame);
+    }
+}
+
+/// Flushes the translation for the page containing `addr` in the address
+/// space rooted at `l4_frame` on every core.
+///
+/// Only the current address space can be invalidated directly, the others
+/// lose their PCID instead.
+pub fn flush_page(l4_frame: RawFrame, addr: VirtAddr) {
+    if l4_frame == AnyPageTable::current_raw() {
+        tlb::flush(x86_64_impl::VirtAddr::new(addr.as_usize() as u64));
+        // `invlpg` doesn't reach the PCID of the user view.
+        #[cfg(feature = "KPTI")]
+        {
+            forget_address_space(l4_frame);
+            super::kpti::flush_user_view();
+        }
+    } else {
+        forget_address_space(l4_frame);
+    }
+    shootdown();
+}
+
+/// Flushes all non-global translations on every core.
+///
+/// Used when the virtual addresses affected by a change aren't known, which
+/// is the case for page tables manipulated through their capabilities.
+pub fn flush_all() {
+    if PCID_ENABLED.load(Ordering::Relaxed) {
+        let mut cache = PCID_CACHE.get().get().borrow_mut();
+        cache.slots = [None; PCID_SLOTS];
+    }
+    // SAFETY: Reloading CR3 as is flushes the current PCID.
+    unsafe {
+        asm!("mov {0}, cr3", "mov cr3, {0}", out(reg) _, options(nostack));
+    }
+    #[cfg(feature = "KPTI")]
+    super::kpti::flush_user_view();
+    shootdown();
+}
+
+/// Makes the other cores flush their TLBs, waiting for them to finish.
+fn shootdown() {
+    // TODO: Send a flush IPI to the other cores once they're brought up.
+}
@@ -0,0 +1,403 @@
+//! PCI configuration space access and enumeration.
+//!
+//! The configuration space is reached through the ECAM regions listed in the
+//! ACPI MCFG table, or through the legacy CF8/CFC ports when there's no MCFG.
+//! Both are kept from userspace: the ECAM frames are mapped by the kernel and
+//! the ports are reserved, so devices can only be configured through their
+//! function capabilities. Every function found at boot is recorded, in bus
+//! order.
+
+use sync::cell::AtomicOnceCell;
+use x86_64_impl::instructions::port::Port;
+
+use super::acpi::ACPI;
+use super::paging::{mmio, PhysAddr, VirtAddr};
+
+/// Maximum number of ECAM regions that are used.
+const MAX_ECAM_REGIONS: usize = 4;
+/// Maximum number of functions recorded during enumeration.
+const MAX_FUNCTIONS: usize = 256;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+const VENDOR_ID: u16 = 0x00;
+const COMMAND: u16 = 0x04;
+const CLASS: u16 = 0x08;
+const HEADER_TYPE: u16 = 0x0C;
+const BAR0: u16 = 0x10;
+const BAR_COUNT: u8 = 6;
+
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+const MULTI_FUNCTION: u32 = 1 << 23;
+
+/// Vendor of the virtio devices.
+const VIRTIO_VENDOR: u16 = 0x1AF4;
+
+const DEVICE_HEADER: u8 = 0;
+const BRIDGE_HEADER: u8 = 1;
+
+/// Registers that decide where a device decodes, which only the kernel
+/// writes: the BARs and the expansion ROM.
+const DEVICE_PROTECTED_REGISTERS: [core::ops::Range<u16>; 2] = [0x10..0x28, 0x30..0x34];
+/// Same for bridges: the BARs, the bus numbers, the forwarding windows with
+/// their upper bits, the expansion ROM and the bridge control.
+const BRIDGE_PROTECTED_REGISTERS: [core::ops::Range<u16>; 2] = [0x10..0x34, 0x38..0x40];
+
+static CONFIG: AtomicOnceCell<ConfigAccess> = AtomicOnceCell::new();
+static FUNCTIONS: AtomicOnceCell<Functions> = AtomicOnceCell::new();
+
+enum ConfigAccess {
+    Ecam([Option<Ecam>; MAX_ECAM_REGIONS]),
+    Legacy,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Ecam {
+    base: VirtAddr,
+    segment_group: u16,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+struct Functions {
+    functions: [Option<PciAddress>; MAX_FUNCTIONS],
+    count: usize,
+}
+
+/// Address of a PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    segment: u16,
+    bus: u8,
+    device: u8,
+    function: u8,
+}
+
+/// A region decoded by a function, as described by one of its BARs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory { base: u64, size: u64 },
+    Io { base: u16, size: u32 },
+}
+
+/// Finds the configuration mechanism and records the functions present.
+///
+/// Must run before any user address space is created, as the ECAM regions
+/// are mapped in the kernel's half.
+pub fn init() {
+    let config = ecam().unwrap_or(ConfigAccess::Legacy);
+    if CONFIG.set(config).is_err() {
+        panic!("PCI initialized twice");
+    }
+    let mut functions = Functions {
+        functions: [None; MAX_FUNCTIONS],
+        count: 0,
+    };
+    for (segment, buses) in segments() {
+        for bus in buses {
+            for device in 0..32 {
+                enumerate_device(&mut functions, segment, bus, device);
+            }
+        }
+    }
+    log::info!("Found {} PCI function(s)", functions.count);
+    if FUNCTIONS.set(functions).is_err() {
+        panic!("PCI initialized twice");
+    }
+}
+
+fn ecam() -> Option<ConfigAccess> {
+    let mcfg = ACPI.mcfg()?;
+    let mut regions = [None; MAX_ECAM_REGIONS];
+    for (slot, region) in regions.iter_mut().zip(mcfg.regions()) {
+        let buses = (region.end_bus - region.start_bus) as usize + 1;
+        let base = mmio::map(PhysAddr::new(region.base_address), buses << 20)
+            .expect("Couldn't map the PCI ECAM region");
+        *slot = Some(Ecam {
+            base,
+            segment_group: region.segment_group,
+            start_bus: region.start_bus,
+            end_bus: region.end_bus,
+        });
+    }
+    log::info!("Using ECAM for the PCI configuration space");
+    Some(ConfigAccess::Ecam(regions))
+}
+
+/// Returns the bus ranges of every segment group.
+fn segments() -> impl Iterator<Item = (u16, core::ops::RangeInclusive<u8>)> {
+    let (ecam, legacy) = match CONFIG.get().unwrap() {
+        ConfigAccess::Ecam(regions) => (Some(regions), None),
+        ConfigAccess::Legacy => (None, Some((0, 0..=u8::MAX))),
+    };
+    ecam.into_iter()
+        .flatten()
+        .flatten()
+        .map(|region| (region.segment_group, region.start_bus..=region.end_bus))
+        .chain(legacy)
+}
+
+fn enumerate_device(functions: &mut Functions, segment: u16, bus: u8, device: u8) {
+    let first = PciAddress {
+        segment,
+        bus,
+        device,
+        function: 0,
+    };
+    if !first.is_present() {
+        return;
+    }
+    let count = if first.read(HEADER_TYPE) & MULTI_FUNCTION != 0 {
+        8
+    } else {
+        1
+    };
+    for function in 0..count {
+        let address = PciAddress { function, ..first };
+        if !address.is_present() {
+            continue;
+        }
+        let id = address.read(VENDOR_ID);
+        let (vendor, device_id) = (id as u16, (id >> 16) as u16);
+        let class = address.read(CLASS) >> 8;
+        log::debug!(
+            "PCI {address}: {vendor:04x}:{device_id:04x}, class {class:06x}{}",
+            if vendor == VIRTIO_VENDOR {
+                " (virtio)"
+            } else {
+                ""
+            }
+        );
+        if functions.count == MAX_FUNCTIONS {
+            log::warn!("Too many PCI functions, ignoring {address}");
+            continue;
+        }
+        functions.functions[functions.count] = Some(address);
+        functions.count += 1;
+    }
+}
+
+/// Returns the `index`-th function found during enumeration.
+pub fn function(index: usize) -> Option<PciAddress> {
+    FUNCTIONS.get()?.functions.get(index).copied().flatten()
+}
+
+/// Size of the configuration space of each function.
+pub fn config_size() -> u16 {
+    match CONFIG.get() {
+        Some(ConfigAccess::Ecam(_)) => 4096,
+        _ => 256,
+    }
+}
+
+/// Returns whether the register at `offset` of a function with the header
+/// type can be written by userspace. Nothing is writable for unknown header
+/// types.
+fn is_writable(header_type: u8, offset: u16) -> bool {
+    let protected = match header_type {
+        DEVICE_HEADER => &DEVICE_PROTECTED_REGISTERS,
+        BRIDGE_HEADER => &BRIDGE_PROTECTED_REGISTERS,
+        _ => return false,
+    };
+    !protected.iter().any(|range| range.contains(&offset))
+}
+
+impl PciAddress {
+    /// Packs the address into a single word as segment, bus, device and
+    /// function, from the highest bits to the lowest.
+    pub fn as_u32(&self) -> u32 {
+        (self.segment as u32) << 16
+            | (self.bus as u32) << 8
+            | (self.device as u32) << 3
+            | self.function as u32
+    }
+
+    /// Returns whether userspace can write the register at `offset`.
+    pub fn is_writable(&self, offset: u16) -> bool {
+        is_writable(self.header_type(), offset)
+    }
+
+    /// Returns the layout of the header, without the multi-function bit.
+    fn header_type(&self) -> u8 {
+        (self.read(HEADER_TYPE) >> 16 & 0x7F) as u8
+    }
+
+    fn is_present(&self) -> bool {
+        self.read(VENDOR_ID) as u16 != 0xFFFF
+    }
+
+    /// Reads the register at the 4-byte aligned `offset`.
+    pub fn read(&self, offset: u16) -> u32 {
+        debug_assert!(offset % 4 == 0 && offset < config_size());
+        match self.ecam_address(offset) {
+            // SAFETY: The ECAM region is mapped and covers the function.
+            Some(address) => unsafe { address.as_ptr::<u32>().read_volatile() },
+            None => {
+                let Some(address) = self.legacy_address(offset) else {
+                    return u32::MAX;
+                };
+                // SAFETY: The configuration ports are reserved for the kernel,
+                // which never accesses them concurrently.
+                unsafe {
+                    Port::new(CONFIG_ADDRESS).write(address);
+                    Port::new(CONFIG_DATA).read()
+                }
+            }
+        }
+    }
+
+    /// Writes the register at the 4-byte aligned `offset`.
+    pub fn write(&self, offset: u16, value: u32) {
+        debug_assert!(offset % 4 == 0 && offset < config_size());
+        match self.ecam_address(offset) {
+            // SAFETY: The ECAM region is mapped and covers the function.
+            Some(address) => unsafe { address.as_mut_ptr::<u32>().write_volatile(value) },
+            None => {
+                let Some(address) = self.legacy_address(offset) else {
+                    return;
+                };
+                // SAFETY: The configuration ports are reserved for the kernel,
+                // which never accesses them concurrently.
+                unsafe {
+                    Port::new(CONFIG_ADDRESS).write(address);
+                    Port::new(CONFIG_DATA).write(value);
+                }
+            }
+        }
+    }
+
+    fn ecam_address(&self, offset: u16) -> Option<VirtAddr> {
+        let ConfigAccess::Ecam(regions) = CONFIG.get()? else {
+            return None;
+        };
+        let region = regions.iter().flatten().find(|region| {
+            region.segment_group == self.segment
+                && (region.start_bus..=region.end_bus).contains(&self.bus)
+        })?;
+        let offset = ((self.bus - region.start_bus) as usize) << 20
+            | (self.device as usize) << 15
+            | (self.function as usize) << 12
+            | offset as usize;
+        Some(VirtAddr::new(region.base.as_usize() + offset))
+    }
+
+    fn legacy_address(&self, offset: u16) -> Option<u32> {
+        if self.segment != 0 || offset >= 256 {
+            return None;
+        }
+        Some(
+            1 << 31
+                | (self.bus as u32) << 16
+                | (self.device as u32) << 11
+                | (self.function as u32) << 8
+                | offset as u32,
+        )
+    }
+
+    /// Sizes the BAR at `index`, returning `None` if it's unused or is the
+    /// upper half of a 64-bit BAR.
+    ///
+    /// Decoding is turned off while the BAR is probed.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        if index >= BAR_COUNT || self.header_type() != DEVICE_HEADER {
+            return None;
+        }
+        // Only the BARs before `index` tell whether it's an upper half.
+        let mut first = 0;
+        while first < index {
+            first += if self.is_wide_bar(first) { 2 } else { 1 };
+        }
+        if first != index {
+            return None;
+        }
+        let offset = BAR0 + index as u16 * 4;
+        let command = self.read(COMMAND) & 0xFFFF;
+        self.write(
+            COMMAND,
+            command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+        );
+        let bar = self.size_bar(index, offset);
+        self.write(COMMAND, command);
+        bar
+    }
+
+    /// Returns whether the BAR at `index` is the lower half of a 64-bit BAR.
+    fn is_wide_bar(&self, index: u8) -> bool {
+        let bar = self.read(BAR0 + index as u16 * 4);
+        bar & 1 == 0 && bar >> 1 & 0b11 == 0b10
+    }
+
+    fn size_bar(&self, index: u8, offset: u16) -> Option<Bar> {
+        let probe = |offset: u16| {
+            let original = self.read(offset);
+            self.write(offset, u32::MAX);
+            let mask = self.read(offset);
+            self.write(offset, original);
+            (original, mask)
+        };
+        let (low, low_mask) = probe(offset);
+        if low & 1 != 0 {
+            let size = (!(low_mask & 0xFFFC) & 0xFFFF) + 1;
+            return (low_mask & 0xFFFC != 0).then_some(Bar::Io {
+                base: (low & 0xFFFC) as u16,
+                size,
+            });
+        }
+        let (high, high_mask, wide) = match low >> 1 & 0b11 {
+            0b00 => (0, u32::MAX, false),
+            0b10 if index + 1 < BAR_COUNT => {
+                let (high, high_mask) = probe(offset + 4);
+                (high, high_mask, true)
+            }
+            _ => return None,
+        };
+        if low_mask & !0xF == 0 && (!wide || high_mask == 0) {
+            return None;
+        }
+        let mask = (high_mask as u64) << 32 | (low_mask & !0xF) as u64;
+        Some(Bar::Memory {
+            base: (high as u64) << 32 | (low & !0xF) as u64,
+            size: (!mask).wrapping_add(1),
+        })
+    }
+}
+
+impl core::fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn decoding_registers_are_protected() {
+        assert!(is_writable(DEVICE_HEADER, COMMAND));
+        assert!(!is_writable(DEVICE_HEADER, BAR0));
+        assert!(!is_writable(DEVICE_HEADER, 0x24));
+        assert!(is_writable(DEVICE_HEADER, 0x28));
+        assert!(!is_writable(DEVICE_HEADER, 0x30));
+        assert!(is_writable(DEVICE_HEADER, 0x3C));
+    }
+
+    #[test_case]
+    fn bridge_windows_are_protected() {
+        assert!(is_writable(BRIDGE_HEADER, COMMAND));
+        // Bus numbers.
+        assert!(!is_writable(BRIDGE_HEADER, 0x18));
+        // Upper bits of the prefetchable window.
+        assert!(!is_writable(BRIDGE_HEADER, 0x28));
+        assert!(!is_writable(BRIDGE_HEADER, 0x2C));
+        assert!(!is_writable(BRIDGE_HEADER, 0x38));
+        assert!(!is_writable(BRIDGE_HEADER, 0x3C));
+        assert!(!is_writable(2, COMMAND));
+    }
+}
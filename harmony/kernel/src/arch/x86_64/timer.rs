@@ -7,6 +7,8 @@ use x86_64_impl::instructions::port::Port;
 
 /// Frequency of the internal oscillator in Hz.
 pub const OSCILATING_FREQ: f32 = 1193182.0;
+/// Frequency of the internal oscillator in whole Hz.
+pub const OSCILATING_FREQ_HZ: u64 = 1193182;
 
 #[allow(missing_copy_implementations)]
 #[derive(Debug)]
@@ -98,3 +100,64 @@ impl PitTimer {
     //     OSCILATING_FREQ / reset_value as f32
     // }
 }
+
+/// Channel 2 of the PIT, gated through the keyboard controller port.
+///
+/// Channel 2 doesn't raise interrupts. Instead, its output can be polled which
+/// makes it useful as a fixed reference for calibrating the other timers.
+#[allow(missing_copy_implementations)]
+#[derive(Debug)]
+pub struct PitChannel2 {
+    mode_port: Port<u8>,
+    ch2_port: Port<u8>,
+    gate_port: Port<u8>,
+}
+
+impl PitChannel2 {
+    /// Constructs a new instance of the PIT's channel 2.
+    ///
+    /// # Safety
+    ///
+    /// There can only be 1 instance of the channel at any time in the program.
+    pub(super) const unsafe fn steal() -> Self {
+        Self {
+            mode_port: Port::new(0x43),
+            ch2_port: Port::new(0x42),
+            gate_port: Port::new(0x61),
+        }
+    }
+
+    /// Starts a one-shot countdown of `ticks` oscillator periods.
+    pub fn start(&mut self, ticks: u16) {
+        // SAFETY: No other side effects, the speaker output stays disabled.
+        unsafe {
+            let gate = self.gate_port.read() & !0b11;
+            // Gate low while programming.
+            self.gate_port.write(gate);
+            // Set PIT to channel 2, mode 0 in low/high byte.
+            self.mode_port.write(0b10110000);
+            self.ch2_port.write((ticks & 0xFF) as u8);
+            self.ch2_port.write(((ticks >> 8) & 0xFF) as u8);
+            // Raising the gate starts the countdown.
+            self.gate_port.write(gate | 0b01);
+        }
+    }
+
+    /// Returns whether the countdown has reached 0.
+    pub fn is_done(&mut self) -> bool {
+        // SAFETY: Reading the gate port has no side effects.
+        unsafe { self.gate_port.read() & 0b10_0000 != 0 }
+    }
+
+    /// Busy-waits until the countdown reaches 0.
+    pub fn wait(&mut self) {
+        while !self.is_done() {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Converts a number of oscillator ticks into nanoseconds.
+    pub const fn ticks_to_nanos(ticks: u16) -> u64 {
+        ticks as u64 * 1_000_000_000 / OSCILATING_FREQ_HZ
+    }
+}
@@ -0,0 +1,218 @@
+//! Kernel accesses to user memory.
+//!
+//! With SMEP, the kernel can't execute user pages, and with SMAP it can't
+//! access them unless RFLAGS.AC is set. The copy helpers here only set it for
+//! the duration of the copy.
+//!
+//! [`copy_from_user`] and [`copy_to_user`] check the range against the current
+//! address space before copying anything. A page fault during the copy (the
+//! mapping may still change underneath, or an upper-level entry may not be
+//! user accessible) makes the copy fail instead of panicking.
+
+use core::arch::{asm, global_asm};
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use kapi::raw::CapError;
+use x86_64_impl::registers::control::{Cr4, Cr4Flags};
+
+use super::paging::page_table::{AnyPageTable, PageTableFlags};
+use super::paging::VirtAddr;
+
+/// End of the addresses userspace can hand to the kernel, leaving out the
+/// last page before the non-canonical hole.
+const USER_END: usize = 0x0000_7FFF_FFFF_F000;
+
+extern "sysv64" {
+    fn __user_copy(dst: *mut u8, src: *const u8, len: usize) -> usize;
+}
+
+extern "C" {
+    static __user_copy_insn: u8;
+    static __user_copy_fixup: u8;
+}
+
+/// Reasons a copy to or from user memory can fail.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UserCopyError {
+    /// Part of the range isn't mapped with the required permissions.
+    NotMapped,
+    /// The copy faulted part way through.
+    Faulted,
+}
+
+impl From<UserCopyError> for CapError {
+    fn from(_value: UserCopyError) -> Self {
+        CapError::InvalidUserAddress
+    }
+}
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables SMEP and SMAP when the CPU supports them.
+pub(super) fn init() {
+    // SAFETY: CPUID is always available in long mode.
+    let features = unsafe { core::arch::x86_64::__cpuid_count(7, 0) }.ebx;
+    let mut flags = Cr4Flags::empty();
+    if features & (1 << 7) != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if features & (1 << 20) != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    // SAFETY: The kernel only touches user memory through the helpers below,
+    // and never executes it.
+    unsafe {
+        Cr4::update(|cr4| cr4.insert(flags));
+    }
+    let smap = flags.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+    SMAP_ENABLED.store(smap, Ordering::Relaxed);
+    log::info!(
+        "SMEP is {}, SMAP is {}",
+        if flags.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION) {
+            "enabled"
+        } else {
+            "not supported"
+        },
+        if smap { "enabled" } else { "not supported" },
+    );
+}
+
+/// Forbids accesses to user memory, in case userspace entered the kernel
+/// with RFLAGS.AC set.
+pub fn forbid_access() {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        // SAFETY: Only clears RFLAGS.AC.
+        unsafe {
+            asm!("clac", options(nomem, nostack));
+        }
+    }
+}
+
+/// Allows accesses to user memory while alive.
+struct UserAccess;
+
+impl UserAccess {
+    fn allow() -> Self {
+        if SMAP_ENABLED.load(Ordering::Relaxed) {
+            // SAFETY: Only sets RFLAGS.AC, which the guard clears again.
+            unsafe {
+                asm!("stac", options(nomem, nostack));
+            }
+        }
+        Self
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        forbid_access();
+    }
+}
+
+/// Checks that `len` bytes starting at `addr` are mapped with `flags` in the
+/// current address space.
+fn validate(addr: VirtAddr, len: usize, flags: PageTableFlags) -> Result<(), UserCopyError> {
+    if len == 0 {
+        return Ok(());
+    }
+    let start = addr.as_usize();
+    let end = start
+        .checked_add(len)
+        .filter(|&end| end <= USER_END)
+        .ok_or(UserCopyError::NotMapped)?;
+    let table = AnyPageTable::current();
+    // SAFETY: The current table is a root table.
+    let addrspace = unsafe { table.as_addrspace() };
+    let mut next = start;
+    for mapping in addrspace.mappings(addr..VirtAddr::new(end)) {
+        if mapping.start.as_usize() > next || !mapping.flags.contains(flags) {
+            return Err(UserCopyError::NotMapped);
+        }
+        next = mapping.start.as_usize() + mapping.size();
+    }
+    if next < end {
+        return Err(UserCopyError::NotMapped);
+    }
+    Ok(())
+}
+
+/// Copies `dst.len()` bytes starting at the user address `src`.
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), UserCopyError> {
+    validate(
+        src,
+        dst.len(),
+        PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE,
+    )?;
+    let _access = UserAccess::allow();
+    // SAFETY: The range was validated, and faults on it are recovered from.
+    match unsafe { __user_copy(dst.as_mut_ptr(), src.as_ptr(), dst.len()) } {
+        0 => Ok(()),
+        _ => Err(UserCopyError::Faulted),
+    }
+}
+
+/// Copies `src` to the user address `dst`.
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), UserCopyError> {
+    validate(
+        dst,
+        src.len(),
+        PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE,
+    )?;
+    let _access = UserAccess::allow();
+    // SAFETY: The range was validated, and faults on it are recovered from.
+    match unsafe { __user_copy(dst.as_mut_ptr(), src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(UserCopyError::Faulted),
+    }
+}
+
+// Copies RDX bytes from RSI to RDI, returning how many were left when a page
+// fault interrupted the copy. The page fault handler resumes faults on the
+// copy instruction right after it (see `fixup`), where RCX holds the number of
+// bytes left.
+global_asm!(
+    ".global __user_copy",
+    ".global __user_copy_insn",
+    ".global __user_copy_fixup",
+    "__user_copy:",
+    "mov rcx, rdx",
+    "__user_copy_insn:",
+    "rep movsb",
+    "__user_copy_fixup:",
+    "mov rax, rcx",
+    "ret",
+);
+
+/// Returns where to resume after a page fault at `rip` in the kernel, if the
+/// fault happened while copying from or to user memory.
+pub fn fixup(rip: VirtAddr) -> Option<VirtAddr> {
+    // SAFETY: The symbols are defined by `__user_copy`.
+    let (insn, fixup) = unsafe { (addr_of!(__user_copy_insn), addr_of!(__user_copy_fixup)) };
+    (rip == VirtAddr::from_ptr(insn)).then(|| VirtAddr::from_ptr(fixup))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn rejects_ranges_outside_of_user_memory() {
+        let mut buf = [0; 16];
+        // Nothing is mapped at the bottom of the kernel's address space.
+        assert_eq!(
+            copy_from_user(&mut buf, VirtAddr::new(0x1000)),
+            Err(UserCopyError::NotMapped)
+        );
+        let kernel = VirtAddr::from_ptr(buf.as_ptr());
+        assert_eq!(
+            copy_from_user(&mut buf.clone(), kernel),
+            Err(UserCopyError::NotMapped)
+        );
+        assert_eq!(
+            copy_to_user(VirtAddr::new(USER_END - 8), &buf),
+            Err(UserCopyError::NotMapped)
+        );
+        assert_eq!(copy_from_user(&mut [], VirtAddr::new(0x1000)), Ok(()));
+    }
+}
@@ -6,10 +6,15 @@ use kapi::raw::{CapError, CapId};
 use sync::cell::AtomicCell;
 use trie::{Ptr, Slot, SlotId, TrieEntry};
 
+use crate::arch::io_ports::PortRange;
 use crate::arch::paging::page_table::AnyPageTable;
-use crate::arch::paging::PAGE_SIZE;
-use crate::component::Thread;
+use crate::arch::paging::{PhysAddr, RawFrame, FRAME_SIZE, PAGE_SIZE};
+use crate::arch::pci::PciAddress;
+use crate::component::{Component, Thread};
+use crate::ipc::Endpoint;
+use crate::irq;
 use crate::kptr::KPtr;
+use crate::notification::Notification;
 
 const SLOT_SIZE: usize = 32;
 const NUM_SLOTS: usize = PAGE_SIZE / SLOT_SIZE;
@@ -59,6 +64,61 @@ impl TryFrom<Resource> for KPtr<Thread> {
     }
 }
 
+impl TryFrom<Resource> for KPtr<Endpoint> {
+    type Error = WrongVariant;
+
+    fn try_from(value: Resource) -> Result<Self, Self::Error> {
+        match value {
+            Resource::Endpoint(endpoint) => Ok(endpoint),
+            _ => Err(WrongVariant),
+        }
+    }
+}
+
+impl TryFrom<Resource> for KPtr<Component> {
+    type Error = WrongVariant;
+
+    fn try_from(value: Resource) -> Result<Self, Self::Error> {
+        match value {
+            Resource::Component(component) => Ok(component),
+            _ => Err(WrongVariant),
+        }
+    }
+}
+
+impl TryFrom<Resource> for KPtr<Notification> {
+    type Error = WrongVariant;
+
+    fn try_from(value: Resource) -> Result<Self, Self::Error> {
+        match value {
+            Resource::Notification(notification) => Ok(notification),
+            _ => Err(WrongVariant),
+        }
+    }
+}
+
+impl TryFrom<Resource> for MemoryRegion {
+    type Error = WrongVariant;
+
+    fn try_from(value: Resource) -> Result<Self, Self::Error> {
+        match value {
+            Resource::MemoryRegion(region) => Ok(region),
+            _ => Err(WrongVariant),
+        }
+    }
+}
+
+impl TryFrom<Resource> for DeviceRegion {
+    type Error = WrongVariant;
+
+    fn try_from(value: Resource) -> Result<Self, Self::Error> {
+        match value {
+            Resource::DeviceRegion(region) => Ok(region),
+            _ => Err(WrongVariant),
+        }
+    }
+}
+
 pub trait CapEntryExtension: Sized {
     fn find(self, cap: CapId) -> Result<impl Ptr<AtomicCapSlot>, CapError>;
     fn index_slot(self, slot: SlotId<NUM_SLOTS>) -> impl Ptr<AtomicCapSlot>;
@@ -154,6 +214,96 @@ pub enum Resource {
         table: KPtr<AnyPageTable>,
         flags: PageCapFlags,
     },
+    Clock,
+    Endpoint(KPtr<Endpoint>),
+    Notification(KPtr<Notification>),
+    Component(KPtr<Component>),
+    MemoryRegion(MemoryRegion),
+    DeviceRegion(DeviceRegion),
+    IoPorts {
+        range: PortRange,
+        /// Thread the range is granted to, which loses it along with the
+        /// capability.
+        grantee: Option<KPtr<Thread>>,
+    },
+    IrqControl,
+    /// Handler of the legacy IRQ line.
+    IrqHandler(u8),
+    Pci,
+    PciFunction(PciAddress),
+}
+
+/// A range of physical frames that can be retyped.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+    /// Index of the first frame.
+    base: u32,
+    /// Number of frames.
+    frames: u32,
+}
+
+impl MemoryRegion {
+    pub fn new(base: RawFrame, frames: usize) -> Self {
+        let base = base.base().as_u64() / FRAME_SIZE;
+        Self {
+            base: base.try_into().expect("Region base out of bounds"),
+            frames: frames.try_into().expect("Region too large"),
+        }
+    }
+
+    /// Returns the frame at `offset` frames into the region.
+    pub fn frame(&self, offset: u32) -> Option<RawFrame> {
+        if offset >= self.frames {
+            return None;
+        }
+        let address = (self.base as u64 + offset as u64) * FRAME_SIZE;
+        Some(RawFrame::from_start_address(PhysAddr::new(address)))
+    }
+
+    /// Splits the region into its first `offset` frames and the rest.
+    pub fn split(&self, offset: u32) -> Option<(Self, Self)> {
+        if offset == 0 || offset >= self.frames {
+            return None;
+        }
+        let lower = Self {
+            base: self.base,
+            frames: offset,
+        };
+        let upper = Self {
+            base: self.base + offset,
+            frames: self.frames - offset,
+        };
+        Some((lower, upper))
+    }
+}
+
+/// A range of physical frames whose device memory can be mapped.
+///
+/// The range may cover RAM too, but only frames typed as device memory can
+/// be mapped through it.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceRegion(MemoryRegion);
+
+impl DeviceRegion {
+    pub fn new(base: RawFrame, frames: usize) -> Self {
+        Self(MemoryRegion::new(base, frames))
+    }
+
+    /// Returns the frame at `offset` frames into the region.
+    pub fn frame(&self, offset: u32) -> Option<RawFrame> {
+        self.0.frame(offset)
+    }
+
+    /// Returns the range of frames covered by the region.
+    pub fn range(&self) -> MemoryRegion {
+        self.0
+    }
+
+    /// Splits the region into its first `offset` frames and the rest.
+    pub fn split(&self, offset: u32) -> Option<(Self, Self)> {
+        let (lower, upper) = self.0.split(offset)?;
+        Some((Self(lower), Self(upper)))
+    }
 }
 
 #[repr(transparent)]
@@ -174,6 +324,29 @@ impl Resource {
     pub fn is_empty(&self) -> bool {
         matches!(self, Self::Empty)
     }
+
+    /// Whether the capability can be copied. An IRQ line has a single
+    /// handler capability, and a port grant belongs to a single capability,
+    /// so those can only be moved.
+    pub fn is_copyable(&self) -> bool {
+        !matches!(self, Self::IrqHandler(_) | Self::IoPorts { .. })
+    }
+
+    /// Releases what the capability holds beyond references once it's
+    /// dropped.
+    pub fn release(self) {
+        match self {
+            Self::IoPorts {
+                range,
+                grantee: Some(thread),
+            } => {
+                // The grant has its own entry in the thread's ports.
+                let _ = Thread::revoke_io_ports(&thread, range);
+            }
+            Self::IrqHandler(line) => irq::release(line),
+            _ => {}
+        }
+    }
 }
 
 impl<T> trie::Ptr<T> for KPtr<T> {}
@@ -1,21 +1,48 @@
 //! A collection of resources provided to userspace threads.
 
 use core::cell::{RefCell, UnsafeCell};
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use kapi::ipc::{CapTransfer, IpcBuffer, TransferMode, IPC_BUFFER_WORDS, MAX_CAP_TRANSFERS};
 use kapi::ops::cap_table::{CapTableOp, ConstructArgs};
+use kapi::ops::clock::ClockOp;
+use kapi::ops::component::ComponentOp;
+use kapi::ops::device_region::DeviceRegionOp;
+use kapi::ops::endpoint::{EndpointOp, Message};
+use kapi::ops::io_ports::IoPortsOp;
+use kapi::ops::irq::{IrqControlOp, IrqHandlerOp};
+use kapi::ops::memory_region::{FrameKind, MemoryRegionOp};
+use kapi::ops::notification::NotificationOp;
+use kapi::ops::page_table::{PageFlags, PageTableOp};
+use kapi::ops::pci::{PciFunctionOp, PciOp};
 use kapi::ops::thread::ThreadOp;
 use kapi::ops::SyscallOp as _;
 use kapi::raw::{CapError, CapId, SyscallArgs};
-use sync::cell::AtomicOnceCell;
+use sync::cell::{AtomicCell, AtomicOnceCell};
 
-use crate::arch::exec::{ControlRegs, ExecCtx, Regs, SaveState};
-use crate::arch::interrupts::SyscallCtx;
-use crate::arch::paging::page_table::{Addrspace, AnyPageTable, PageTableFlags};
-use crate::arch::paging::{Page, RawFrame, VirtAddr};
-use crate::caps::{CapEntryExtension as _, PageCapFlags, RawCapEntry, Resource};
+use crate::arch::clock;
+use crate::arch::exec::{ControlRegs, ExecCtx, NoopSaver, Regs, SaveState};
+use crate::arch::interrupts::{self, IrqCtx, SyscallCtx};
+use crate::arch::io_ports::{self, PortRange, PortSet};
+use crate::arch::paging::page_table::{
+    Addrspace, AnyPageTable, PageTableFlags, PageTableLevel, PageTableOffset,
+};
+use crate::arch::paging::{pat, tlb, Page, PhysAddr, RawFrame, VirtAddr, FRAME_SIZE, PAGE_SIZE};
+use crate::arch::pci::{self, Bar};
+use crate::caps::{
+    CapEntryExtension as _, DeviceRegion, MemoryRegion, PageCapFlags, RawCapEntry, Resource,
+};
 use crate::core_local::CoreLocal;
+use crate::ipc::Endpoint;
+use crate::irq;
 use crate::kptr::KPtr;
-use crate::UNTYPED_MEMORY_OFFSET;
+use crate::notification::Notification;
+use crate::retyping::{KernelFrame, RetypeError, State, UserFrame};
+
+/// End of the lower half of the address space, which belongs to userspace.
+const USER_SPACE_END: usize = 0x0000_8000_0000_0000;
+/// Maximum number of nested component invocations on a thread.
+const MAX_INVOCATION_DEPTH: usize = 12;
 
 static ACTIVE_THREAD: AtomicOnceCell<CoreLocal<RefCell<Option<KPtr<Thread>>>>> =
     AtomicOnceCell::new();
@@ -25,18 +52,76 @@ pub fn init() {
     ACTIVE_THREAD.set(threads).unwrap();
 }
 
-// TODO: Implement thread migration
+/// A protection domain that threads can migrate into.
+///
+/// Invoking a component switches the calling thread to the component's
+/// address space and resource table, starting at its entry point. The thread
+/// comes back to the invoking component through a matching return.
+#[repr(align(4096))]
+pub struct Component {
+    entry: usize,
+    /// Stack every invocation starts on.
+    stack_pointer: usize,
+    resources: KPtr<RawCapEntry>,
+    page_table: KPtr<AnyPageTable>,
+}
+
+impl Component {
+    pub fn new(
+        entry: usize,
+        stack_pointer: usize,
+        resources: KPtr<RawCapEntry>,
+        page_table: KPtr<AnyPageTable>,
+    ) -> Self {
+        Self {
+            entry,
+            stack_pointer,
+            resources,
+            page_table,
+        }
+    }
+}
+
+/// State of the invoking component, restored when the invocation returns.
+struct Invocation {
+    regs: Regs,
+    l4_frame: RawFrame,
+    resources: KPtr<RawCapEntry>,
+}
+
+/// Kernel-managed stack of the invocations a thread is currently in.
+#[derive(Default)]
+struct InvocationStack {
+    invocations: [Option<Invocation>; MAX_INVOCATION_DEPTH],
+    depth: usize,
+}
+
 /// A user-space thread that provides a mechanism for dispatching.
 ///
 /// Each thread has its own address space, execution context, and resource
-/// table.
+/// table. The address space and resource table change as the thread migrates
+/// across components.
 #[repr(align(4096))]
 pub struct Thread {
     // FIXME: This is not the correct way to do this...
     exec_ctx: UnsafeCell<ExecCtx>,
-    resources: KPtr<RawCapEntry>,
+    resources: AtomicCell<KPtr<RawCapEntry>>,
+    /// Thread to switch to when this one replies.
+    reply_to: AtomicCell<Option<KPtr<Thread>>>,
+    /// User page used to pass messages longer than the message registers.
+    ipc_buffer: AtomicCell<Option<UserFrame>>,
+    invocations: AtomicCell<InvocationStack>,
+    /// I/O ports the thread can access from userspace.
+    io_ports: AtomicCell<PortSet>,
+    /// Whether the thread was interrupted rather than stopped in a syscall,
+    /// in which case its registers must be restored as they were.
+    interrupted: AtomicBool,
+    /// Whether the thread is blocked in a call, waiting for the reply.
+    calling: AtomicBool,
 }
 
+const _: () = assert!(core::mem::size_of::<Thread>() == PAGE_SIZE);
+
 impl Thread {
     pub fn new(regs: Regs, l4_table: KPtr<AnyPageTable>, resources: KPtr<RawCapEntry>) -> Self {
         let exec_ctx = ExecCtx::new(l4_table.into_raw(), regs);
@@ -46,8 +131,340 @@ impl Thread {
     pub fn new_with_ctx(ctx: ExecCtx, resources: KPtr<RawCapEntry>) -> Self {
         Self {
             exec_ctx: UnsafeCell::new(ctx),
-            resources,
+            resources: AtomicCell::new(resources),
+            reply_to: AtomicCell::new(None),
+            ipc_buffer: AtomicCell::new(None),
+            invocations: AtomicCell::default(),
+            io_ports: AtomicCell::default(),
+            interrupted: AtomicBool::new(false),
+            calling: AtomicBool::new(false),
+        }
+    }
+
+    /// The resource table of the component the thread is currently in.
+    pub fn resources(&self) -> KPtr<RawCapEntry> {
+        self.resources.get_cloned()
+    }
+
+    /// Sets the thread to switch to on reply, returning the previous one.
+    pub fn set_reply_to(&self, thread: Option<KPtr<Thread>>) -> Option<KPtr<Thread>> {
+        self.reply_to.replace(thread)
+    }
+
+    /// Takes the thread that called into this one, for replying to it.
+    ///
+    /// A thread this one preempted isn't a caller: it's left in place to be
+    /// resumed once this one blocks.
+    pub fn take_caller(&self) -> Option<KPtr<Thread>> {
+        let caller = self.reply_to.replace(None)?;
+        if caller.interrupted.load(Ordering::Relaxed) {
+            self.reply_to.set(Some(caller));
+            return None;
+        }
+        Some(caller)
+    }
+
+    /// Takes the thread to switch to when this one blocks.
+    ///
+    /// A caller is left in place, as it's owed a reply rather than a return
+    /// from its call.
+    pub fn take_next_on_block(&self) -> Option<KPtr<Thread>> {
+        let next = self.reply_to.replace(None)?;
+        if next.calling.load(Ordering::Relaxed) {
+            self.reply_to.set(Some(next));
+            return None;
+        }
+        Some(next)
+    }
+
+    /// Marks the thread as blocked in a call until it's dispatched again.
+    pub fn mark_calling(&self) {
+        self.calling.store(true, Ordering::Relaxed);
+    }
+
+    /// Sets up the thread's saved registers such that its pending syscall
+    /// returns successfully with the message once it's dispatched.
+    pub fn deliver(&self, message: Message) {
+        if self.interrupted.load(Ordering::Relaxed) {
+            return;
+        }
+        // SAFETY: The thread isn't running so nothing else touches its context.
+        let regs = unsafe { (*self.exec_ctx.get()).regs_mut() };
+        let [a, b, c, d] = message;
+        regs.scratch.rax = 0;
+        regs.scratch.rdx = a as u64;
+        regs.scratch.rcx = b as u64;
+        regs.scratch.r8 = c as u64;
+        regs.scratch.r9 = d as u64;
+    }
+
+    /// Sets up the thread's saved registers such that its pending syscall
+    /// returns successfully with `value`.
+    pub fn set_return_value(&self, value: usize) {
+        if self.interrupted.load(Ordering::Relaxed) {
+            return;
+        }
+        // SAFETY: The thread isn't running so nothing else touches its context.
+        let regs = unsafe { (*self.exec_ctx.get()).regs_mut() };
+        regs.scratch.rax = value as u64;
+    }
+
+    /// Registers the user page at `address` as the thread's IPC buffer.
+    ///
+    /// The page must be mapped as writable and user accessible in the thread's
+    /// address space. An address of 0 unregisters the current buffer.
+    pub fn set_ipc_buffer(&self, address: usize) -> Result<(), CapError> {
+        if address == 0 {
+            self.ipc_buffer.set(None);
+            return Ok(());
+        }
+        let address = VirtAddr::try_new(address).map_err(|_| CapError::InvalidArgument)?;
+        if address.as_usize() >= USER_SPACE_END {
+            return Err(CapError::InvalidArgument);
+        }
+        let page = Page::try_from_start_address(address).map_err(|_| CapError::InvalidArgument)?;
+        let (frame, flags, _level) = self
+            .addrspace()
+            .get(page)
+            .ok_or(CapError::InvalidArgument)?;
+        if !flags.contains(
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+        ) {
+            return Err(CapError::InvalidArgument);
+        }
+        let frame = frame.try_as_user().map_err(|_| CapError::FrameNotUser)?;
+        self.ipc_buffer.set(Some(frame));
+        Ok(())
+    }
+
+    /// Lets the thread use the ports in `range`.
+    ///
+    /// Fails if the thread already holds as many ranges as it can.
+    pub fn grant_io_ports(this: &KPtr<Self>, range: PortRange) -> Result<(), CapError> {
+        if !this.io_ports.with(|ports| ports.insert(range)) {
+            return Err(CapError::ResourceInUse);
+        }
+        Self::reload_io_ports(this);
+        Ok(())
+    }
+
+    /// Takes one grant of the ports in `range` back from the thread.
+    pub fn revoke_io_ports(this: &KPtr<Self>, range: PortRange) -> Result<(), CapError> {
+        if !this.io_ports.with(|ports| ports.remove(range)) {
+            return Err(CapError::NotFound);
+        }
+        Self::reload_io_ports(this);
+        Ok(())
+    }
+
+    /// Reloads the ports of the running thread if `this` is it, other threads
+    /// get theirs when they're dispatched.
+    fn reload_io_ports(this: &KPtr<Self>) {
+        if Self::current().as_ref() == Some(this) {
+            io_ports::load(this.io_ports.get());
+        }
+    }
+
+    /// Copies the long message in `from`'s IPC buffer into `to`'s, transferring
+    /// the capabilities it names along the way.
+    ///
+    /// Fails if the message is invalid or if `to` can't receive it, in which
+    /// case neither the buffers nor the capability tables are modified.
+    pub fn transfer_message(from: &Thread, to: &Thread) -> Result<(), CapError> {
+        // The buffers are locked one at a time since `from` and `to` may be the
+        // same thread. Our own reference keeps the source alive meanwhile.
+        let source_frame = from
+            .ipc_buffer
+            .with(|buffer| buffer.as_ref().map(|frame| frame.frame().try_as_user()))
+            .transpose()
+            .map_err(|_| CapError::Internal)?;
+        let source = source_frame
+            .as_ref()
+            .map(|frame| -> *const IpcBuffer { frame.frame().base().to_virtual().as_ptr() });
+        // SAFETY: The buffer is a user frame we hold a reference to. Its
+        // contents are only read as plain words.
+        let (length, transfer_count) = source.map_or((0, 0), |source| unsafe {
+            (
+                core::ptr::addr_of!((*source).length).read_volatile(),
+                core::ptr::addr_of!((*source).transfer_count).read_volatile(),
+            )
+        });
+        if length > IPC_BUFFER_WORDS || transfer_count > MAX_CAP_TRANSFERS {
+            return Err(CapError::InvalidArgument);
+        }
+        // Userspace may change the descriptors at any time so we work on a
+        // snapshot of them.
+        let mut transfers =
+            [CapTransfer::new(CapId::new(0), CapId::new(0), TransferMode::Copy); MAX_CAP_TRANSFERS];
+        let transfers = &mut transfers[..transfer_count];
+        if let Some(source) = source {
+            // SAFETY: Same as above, and any bit pattern is a valid descriptor.
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    core::ptr::addr_of!((*source).transfers) as *const CapTransfer,
+                    transfers.as_mut_ptr(),
+                    transfer_count,
+                );
+            }
+        }
+        to.ipc_buffer.with(|destination| {
+            let Some(destination) = destination else {
+                return if length == 0 && transfer_count == 0 {
+                    Ok(())
+                } else {
+                    Err(CapError::InvalidArgument)
+                };
+            };
+            Self::transfer_caps(from, to, transfers)?;
+            let destination: *mut IpcBuffer = destination.frame().base().to_virtual().as_mut_ptr();
+            // SAFETY: Both buffers are user frames we hold references to.
+            // They may be the same frame, so the copy may overlap.
+            unsafe {
+                if let Some(source) = source {
+                    core::ptr::copy(
+                        core::ptr::addr_of!((*source).words) as *const usize,
+                        core::ptr::addr_of_mut!((*destination).words) as *mut usize,
+                        length,
+                    );
+                }
+                core::ptr::copy_nonoverlapping(
+                    transfers.as_ptr(),
+                    core::ptr::addr_of_mut!((*destination).transfers) as *mut CapTransfer,
+                    transfer_count,
+                );
+                core::ptr::addr_of_mut!((*destination).length).write_volatile(length);
+                core::ptr::addr_of_mut!((*destination).transfer_count)
+                    .write_volatile(transfer_count);
+            }
+            Ok(())
+        })
+    }
+
+    /// Copies or moves the capabilities from `from`'s table into `to`'s.
+    ///
+    /// Copies share the underlying resource, just like `CapTableOp::Copy`.
+    fn transfer_caps(
+        from: &Thread,
+        to: &Thread,
+        transfers: &[CapTransfer],
+    ) -> Result<(), CapError> {
+        // Validate everything upfront so that failures don't leave the tables
+        // half-way through the transfer.
+        for (i, transfer) in transfers.iter().enumerate() {
+            let mode = transfer.mode().ok_or(CapError::InvalidArgument)?;
+            let others = transfers[..i].iter().chain(&transfers[i + 1..]);
+            for other in others {
+                if other.destination == transfer.destination {
+                    return Err(CapError::InvalidArgument);
+                }
+                if mode == TransferMode::Move && other.source == transfer.source {
+                    return Err(CapError::InvalidArgument);
+                }
+            }
+            let source = from.resources().get_capability(transfer.source)?.resource;
+            if source.is_empty() {
+                return Err(CapError::NotFound);
+            }
+            if mode == TransferMode::Copy && !source.is_copyable() {
+                return Err(CapError::InvalidArgument);
+            }
+            if !to
+                .resources()
+                .get_capability(transfer.destination)?
+                .resource
+                .is_empty()
+            {
+                return Err(CapError::ResourceInUse);
+            }
+        }
+        for transfer in transfers {
+            let source = from.resources().find(transfer.source)?;
+            let resource = match transfer.mode() {
+                Some(TransferMode::Move) => {
+                    let mut resource = Resource::Empty;
+                    source.change(|slot| resource = core::mem::take(&mut slot.resource));
+                    resource
+                }
+                _ => source.get().resource,
+            };
+            to.resources()
+                .find(transfer.destination)?
+                .change(|slot| slot.resource = resource);
+        }
+        Ok(())
+    }
+
+    /// Migrates the current thread into the component, passing it the message.
+    ///
+    /// The invoking component's state is saved on the thread's invocation
+    /// stack. Only returns on failure.
+    pub fn invoke(this: KPtr<Self>, component: KPtr<Component>, message: Message) -> CapError {
+        // SAFETY: Invocations are only performed from syscalls.
+        let ctx = unsafe { SyscallCtx::current() };
+        let exec_ctx = this.exec_ctx.get();
+        let pushed = this.invocations.with(|stack| {
+            let Some(slot) = stack.invocations.get_mut(stack.depth) else {
+                return false;
+            };
+            // SAFETY: The thread is the one running the syscall, so nothing
+            // else touches its context.
+            let (regs, l4_frame) = unsafe {
+                ctx.save_state((*exec_ctx).regs_mut());
+                (*(*exec_ctx).regs(), (*exec_ctx).l4_frame())
+            };
+            *slot = Some(Invocation {
+                regs,
+                l4_frame,
+                resources: this.resources.replace(component.resources.clone()),
+            });
+            stack.depth += 1;
+            true
+        });
+        if !pushed {
+            return CapError::InvalidOp;
+        }
+        // SAFETY: Same as above. The page table reference is released on return.
+        unsafe {
+            (*exec_ctx).set_l4_frame(component.page_table.clone().into_raw());
+            *(*exec_ctx).regs_mut() = Regs {
+                control: ControlRegs {
+                    rip: component.entry as u64,
+                    rsp: component.stack_pointer as u64,
+                    rflags: 0x202,
+                },
+                ..Default::default()
+            };
         }
+        drop(component);
+        this.deliver(message);
+        Thread::dispatch(this, NoopSaver::new());
+    }
+
+    /// Returns from the current invocation into the invoking component,
+    /// passing it the message. Only returns on failure.
+    pub fn return_from_invocation(this: KPtr<Self>, message: Message) -> CapError {
+        let invocation = this.invocations.with(|stack| {
+            stack.depth = stack.depth.checked_sub(1)?;
+            stack.invocations[stack.depth].take()
+        });
+        let Some(invocation) = invocation else {
+            return CapError::InvalidOp;
+        };
+        let exec_ctx = this.exec_ctx.get();
+        // SAFETY: The thread is the one running the syscall, so nothing else
+        // touches its context. The component's page table was referenced on
+        // invocation.
+        unsafe {
+            let component_table = (*exec_ctx).l4_frame();
+            (*exec_ctx).set_l4_frame(invocation.l4_frame);
+            *(*exec_ctx).regs_mut() = invocation.regs;
+            drop(KPtr::<AnyPageTable>::from_frame_unchecked(
+                KernelFrame::from_raw(component_table),
+            ));
+        }
+        this.resources.set(invocation.resources);
+        this.deliver(message);
+        Thread::dispatch(this, NoopSaver::new());
     }
 
     pub fn addrspace(&self) -> Addrspace<'_> {
@@ -86,14 +503,186 @@ impl Thread {
             current.replace(this.clone());
         }
         log::info!("Set the active thread");
-        unsafe { (*this.exec_ctx.get()).dispatch() }
+        io_ports::load(this.io_ports.get());
+        this.interrupted.store(false, Ordering::Relaxed);
+        this.calling.store(false, Ordering::Relaxed);
+        // We never come back here, so the reference has to be released now. The
+        // active thread keeps the thread alive.
+        let exec_ctx = this.exec_ctx.get();
+        drop(this);
+        unsafe { (*exec_ctx).dispatch() }
+    }
+
+    /// Switches to `next` from an interrupt that came in while userspace was
+    /// running.
+    ///
+    /// The interrupted thread resumes where it was once `next` blocks. It
+    /// isn't expecting a reply, so `next` can't reply to it.
+    pub fn preempt(next: KPtr<Self>) -> ! {
+        let current = Self::current().expect("Interrupted without an active thread");
+        current.interrupted.store(true, Ordering::Relaxed);
+        next.set_reply_to(Some(current));
+        // SAFETY: Interrupts only come in from userspace.
+        let ctx = unsafe { IrqCtx::current() };
+        Self::dispatch(next, ctx);
     }
 }
 
 impl Thread {
-    pub fn exercise_cap(&self, capability: CapId, args: SyscallArgs) -> Result<usize, CapError> {
-        let slot = self.resources.clone().find(capability)?.get();
-        match slot.resource {
+    /// Applies the operation to a page table of the level in `cap_flags`.
+    fn exercise_page_table(
+        &self,
+        table: KPtr<AnyPageTable>,
+        cap_flags: PageCapFlags,
+        operation: PageTableOp,
+    ) -> Result<usize, CapError> {
+        let level = PageTableLevel::try_new(cap_flags.level()).map_err(|_| CapError::Internal)?;
+        let entry = |offset: u16| {
+            let offset =
+                PageTableOffset::new(offset).map_err(|_| CapError::PageOffsetOutOfBounds)?;
+            // The upper half of the top-level tables belongs to the kernel.
+            if level.level() == 4 && !offset.is_lower_half() {
+                return Err(CapError::InvalidArgument);
+            }
+            Ok(table.get(offset))
+        };
+        match operation {
+            PageTableOp::Link {
+                offset,
+                table: child,
+                flags,
+            } => {
+                if flags.contains(PageFlags::WRITE_COMBINING) {
+                    return Err(CapError::InvalidArgument);
+                }
+                let entry = entry(offset)?;
+                let (child, child_flags): (KPtr<AnyPageTable>, PageCapFlags) =
+                    self.resources().get_resource_as(child)?;
+                if child_flags.level() + 1 != level.level() {
+                    return Err(CapError::InvalidArgument);
+                }
+                // SAFETY: The child is a lower-level table, so it can't be used
+                // to reach the kernel mappings.
+                unsafe { entry.try_set(child.frame(), user_page_flags(flags)) }
+                    .map_err(|_| CapError::ResourceInUse)?;
+                // The entry keeps the table alive until it's unlinked.
+                let _ = child.into_raw();
+                Ok(0)
+            }
+            PageTableOp::Map {
+                offset,
+                region,
+                frame,
+                flags,
+            } => {
+                let entry = entry(offset)?;
+                let (region, device) = match self.resources().get_capability(region)?.resource {
+                    Resource::MemoryRegion(region) => (region, false),
+                    Resource::DeviceRegion(region) => (region.range(), true),
+                    _ => return Err(CapError::InvalidArgument),
+                };
+                if level.level() == 4 || (flags.contains(PageFlags::WRITE_COMBINING) && !device) {
+                    return Err(CapError::InvalidArgument);
+                }
+                // The whole unit must be within the region.
+                let frames = level.page_size() / PAGE_SIZE;
+                let last = frame
+                    .checked_add(frames as u32 - 1)
+                    .ok_or(CapError::FrameOutsideOfRegion)?;
+                region.frame(last).ok_or(CapError::FrameOutsideOfRegion)?;
+                let first = frame;
+                let frame = region.frame(first).ok_or(CapError::FrameOutsideOfRegion)?;
+                if frame.base().as_u64() as usize % level.page_size() != 0 {
+                    return Err(CapError::InvalidArgument);
+                }
+                let mut entry_flags = user_page_flags(flags);
+                if !level.is_bottom() {
+                    entry_flags |= PageTableFlags::HUGE_PAGE;
+                }
+                if !level.is_bottom() && !level.supports_huge_pages() {
+                    return Err(CapError::InvalidArgument);
+                }
+                if device {
+                    // RAM is never reachable through a device region, whatever
+                    // range it covers.
+                    if !(first..=last).all(|i| region.frame(i).is_some_and(|f| f.is_device())) {
+                        return Err(CapError::FrameNotDevice);
+                    }
+                    entry_flags |= DEVICE_MAPPING;
+                    entry_flags |= if flags.contains(PageFlags::WRITE_COMBINING) {
+                        pat::WRITE_COMBINING
+                    } else {
+                        pat::UNCACHEABLE
+                    };
+                    // SAFETY: Device memory isn't managed by the kernel, so the
+                    // entry doesn't need to keep anything alive.
+                    unsafe { entry.try_set(frame, entry_flags) }
+                        .map_err(|_| CapError::ResourceInUse)?;
+                    return Ok(0);
+                }
+                // The reference is taken before the frames become reachable.
+                let user_frame = reference_user_frames(frame, level.page_size())?;
+                // SAFETY: The frames are typed as user and referenced by the entry.
+                unsafe { entry.try_set(frame, entry_flags) }
+                    .map_err(|_| CapError::ResourceInUse)?;
+                // The entry keeps the frames typed as user until it's unlinked.
+                let _ = user_frame.into_raw();
+                Ok(0)
+            }
+            PageTableOp::Unlink { offset } => {
+                let entry = entry(offset)?;
+                // SAFETY: Only lower-half entries are modified.
+                let (frame, flags) = unsafe { entry.reset() }.ok_or(CapError::NotFound)?;
+                // The references can only be released once no TLB can reach
+                // the entry anymore.
+                tlb::flush_all();
+                if flags.contains(DEVICE_MAPPING) {
+                    // Device memory holds no references.
+                } else if level.is_bottom() || flags.contains(PageTableFlags::HUGE_PAGE) {
+                    // SAFETY: The frames were referenced when they were mapped.
+                    drop(unsafe { UserFrame::from_raw(frame) });
+                } else {
+                    // SAFETY: The table was referenced when it was linked.
+                    drop(unsafe {
+                        KPtr::<AnyPageTable>::from_frame_unchecked(KernelFrame::from_raw(frame))
+                    });
+                }
+                Ok(0)
+            }
+            PageTableOp::ChangeFlags { offset, flags } => {
+                let entry = entry(offset)?;
+                // The memory type of a mapping is fixed when it's created.
+                if flags.contains(PageFlags::WRITE_COMBINING) {
+                    return Err(CapError::InvalidArgument);
+                }
+                let current = entry.flags().ok_or(CapError::NotFound)?;
+                let kept = PageTableFlags::HUGE_PAGE | DEVICE_MAPPING | pat::CACHING;
+                let flags = user_page_flags(flags) | (current & kept);
+                // SAFETY: Only the access rights of lower-half entries are modified.
+                unsafe {
+                    entry.set_flags(flags);
+                }
+                tlb::flush_all();
+                Ok(0)
+            }
+        }
+    }
+
+    /// Applies the operation to the capability in the thread's resources.
+    ///
+    /// Takes ownership of the thread as some operations switch to another
+    /// thread and never return.
+    pub fn exercise_cap(
+        this: KPtr<Self>,
+        capability: CapId,
+        args: SyscallArgs,
+    ) -> Result<usize, CapError> {
+        // Returning from an invocation is authorized by the invocation itself.
+        if let Ok(ComponentOp::Return { message }) = ComponentOp::from_args(args) {
+            return Err(Thread::return_from_invocation(this, message));
+        }
+        let resource = this.resources().find(capability)?.get().resource;
+        match resource {
             Resource::Empty => Err(CapError::NotFound),
             Resource::CapEntry(capability_table) => {
                 let operation =
@@ -104,7 +693,7 @@ impl Thread {
                         slot,
                     } => {
                         let other_table: KPtr<RawCapEntry> =
-                            self.resources.clone().get_resource_as(other_table_cap)?;
+                            this.resources().get_resource_as(other_table_cap)?;
                         let slot = capability_table.index_slot(slot);
                         slot.change(|cap| {
                             cap.child = Some(other_table);
@@ -118,24 +707,18 @@ impl Thread {
                         });
                         Ok(0)
                     }
-                    CapTableOp::Construct { kind, region, slot } => {
-                        if region > RawFrame::memory_limit() {
-                            return Err(CapError::InvalidArgument);
-                        }
-                        let page_address = region + UNTYPED_MEMORY_OFFSET;
-                        let region = Page::try_from_start_address(
-                            VirtAddr::try_new(page_address)
-                                .map_err(|_| CapError::InvalidArgument)?,
-                        )
-                        .map_err(|_| CapError::InvalidArgument)?;
-
-                        let (frame, flags) = self
-                            .addrspace()
-                            .get(region)
-                            .ok_or(CapError::InvalidArgument)?;
-                        if !flags.contains(PageTableFlags::PRESENT) {
-                            return Err(CapError::InvalidArgument);
+                    CapTableOp::Construct {
+                        kind,
+                        region,
+                        offset,
+                        slot,
+                    } => {
+                        let destination = capability_table.index_slot(slot);
+                        if !destination.get().resource.is_empty() {
+                            return Err(CapError::ResourceInUse);
                         }
+                        let region: MemoryRegion = this.resources().get_resource_as(region)?;
+                        let frame = region.frame(offset).ok_or(CapError::FrameOutsideOfRegion)?;
                         let resource = match kind {
                             ConstructArgs::CapTable => {
                                 let ptr = KPtr::new(frame, RawCapEntry::default())
@@ -157,10 +740,10 @@ impl Thread {
                                     ..Default::default()
                                 };
                                 let cap_table: KPtr<RawCapEntry> =
-                                    self.resources.clone().get_resource_as(cap_table)?;
+                                    this.resources().get_resource_as(cap_table)?;
                                 let (page_table, flags): (KPtr<AnyPageTable>, PageCapFlags) =
-                                    self.resources.clone().get_resource_as(page_table)?;
-                                if !flags.level() == 4 {
+                                    this.resources().get_resource_as(page_table)?;
+                                if flags.level() != 4 {
                                     return Err(CapError::InvalidArgument);
                                 }
                                 Resource::Thread(
@@ -173,24 +756,63 @@ impl Thread {
                                     return Err(CapError::InvalidArgument);
                                 }
                                 let table = if level == 4 {
-                                    AnyPageTable::clone_kernel()
+                                    AnyPageTable::new_l4(frame)
                                 } else {
-                                    AnyPageTable::new()
+                                    KPtr::new(frame, AnyPageTable::new())
                                 };
                                 let flags = PageCapFlags::new(level);
                                 Resource::PageTable {
-                                    table: KPtr::new(frame, table)
-                                        .map_err(|_| CapError::InvalidArgument)?,
+                                    table: table.map_err(|_| CapError::InvalidArgument)?,
                                     flags,
                                 }
                             }
+                            ConstructArgs::Endpoint => Resource::Endpoint(
+                                KPtr::new(frame, Endpoint::new())
+                                    .map_err(|_| CapError::InvalidArgument)?,
+                            ),
+                            ConstructArgs::Notification => Resource::Notification(
+                                KPtr::new(frame, Notification::new())
+                                    .map_err(|_| CapError::InvalidArgument)?,
+                            ),
+                            ConstructArgs::Component {
+                                entry,
+                                stack_pointer,
+                                cap_table,
+                                page_table,
+                            } => {
+                                let cap_table: KPtr<RawCapEntry> =
+                                    this.resources().get_resource_as(cap_table)?;
+                                let (page_table, flags): (KPtr<AnyPageTable>, PageCapFlags) =
+                                    this.resources().get_resource_as(page_table)?;
+                                if flags.level() != 4
+                                    || entry >= USER_SPACE_END
+                                    || stack_pointer >= USER_SPACE_END
+                                {
+                                    return Err(CapError::InvalidArgument);
+                                }
+                                let component =
+                                    Component::new(entry, stack_pointer, cap_table, page_table);
+                                Resource::Component(
+                                    KPtr::new(frame, component)
+                                        .map_err(|_| CapError::InvalidArgument)?,
+                                )
+                            }
                         };
-                        capability_table.index_slot(slot).change(|cap| {
+                        destination.change(|cap| {
                             cap.resource = resource;
                         });
                         Ok(0)
                     }
-                    CapTableOp::Drop { slot: _ } => todo!(),
+                    CapTableOp::Drop { slot } => {
+                        let slot = capability_table.index_slot(slot);
+                        let mut resource = Resource::Empty;
+                        slot.change(|cap| resource = core::mem::take(&mut cap.resource));
+                        if resource.is_empty() {
+                            return Err(CapError::NotFound);
+                        }
+                        resource.release();
+                        Ok(0)
+                    }
                     CapTableOp::Copy {
                         slot: _,
                         other_table_cap: _,
@@ -202,13 +824,335 @@ impl Thread {
                 let operation = ThreadOp::from_args(args).map_err(|_| CapError::InvalidArgument)?;
                 match operation {
                     ThreadOp::Activate => {
+                        // The activated thread hands control back to us when it
+                        // replies.
+                        thread.set_reply_to(Some(this));
                         let ctx = unsafe { SyscallCtx::current() };
                         Thread::dispatch(thread, ctx);
                     }
                     ThreadOp::ChangeAffinity => todo!(),
+                    ThreadOp::SetIpcBuffer { address } => {
+                        thread.set_ipc_buffer(address)?;
+                        Ok(0)
+                    }
+                }
+            }
+            Resource::PageTable { table, flags } => {
+                let operation =
+                    PageTableOp::from_args(args).map_err(|_| CapError::InvalidArgument)?;
+                this.exercise_page_table(table, flags, operation)
+            }
+            Resource::Clock => {
+                let operation = ClockOp::from_args(args).map_err(|_| CapError::InvalidArgument)?;
+                match operation {
+                    ClockOp::Monotonic => Ok(clock::now() as usize),
+                }
+            }
+            Resource::Endpoint(endpoint) => {
+                let operation =
+                    EndpointOp::from_args(args).map_err(|_| CapError::InvalidArgument)?;
+                Err(match operation {
+                    EndpointOp::Call { message } => Endpoint::call(endpoint, this, message),
+                    EndpointOp::Reply { message } => {
+                        drop(endpoint);
+                        Endpoint::reply(this, message)
+                    }
+                    EndpointOp::ReplyRecv { message } => {
+                        Endpoint::reply_recv(endpoint, this, message)
+                    }
+                })
+            }
+            Resource::Notification(notification) => {
+                let operation =
+                    NotificationOp::from_args(args).map_err(|_| CapError::InvalidArgument)?;
+                match operation {
+                    NotificationOp::Signal { bits } => {
+                        Ok(Notification::signal_from(notification, this, bits))
+                    }
+                    NotificationOp::Wait => Notification::wait(notification, this),
+                    NotificationOp::Poll => Ok(notification.poll() as usize),
+                }
+            }
+            Resource::Component(component) => {
+                let operation =
+                    ComponentOp::from_args(args).map_err(|_| CapError::InvalidArgument)?;
+                match operation {
+                    ComponentOp::Invoke { message } => {
+                        Err(Thread::invoke(this, component, message))
+                    }
+                    // Handled above as it doesn't need a capability.
+                    ComponentOp::Return { .. } => unreachable!(),
+                }
+            }
+            Resource::MemoryRegion(region) => {
+                let operation =
+                    MemoryRegionOp::from_args(args).map_err(|_| CapError::InvalidArgument)?;
+                match operation {
+                    MemoryRegionOp::Retype { offset, kind } => {
+                        let frame = region.frame(offset).ok_or(CapError::FrameOutsideOfRegion)?;
+                        let state = match kind {
+                            FrameKind::User => State::User,
+                            FrameKind::Kernel => State::Kernel,
+                        };
+                        frame
+                            .try_retype(state)
+                            .map_err(|_| CapError::ResourceInUse)?;
+                        Ok(0)
+                    }
+                    MemoryRegionOp::Split { offset, slot } => {
+                        let (lower, upper) =
+                            region.split(offset).ok_or(CapError::InvalidArgument)?;
+                        let destination = this.resources().find(slot)?;
+                        if !destination.get().resource.is_empty() {
+                            return Err(CapError::ResourceInUse);
+                        }
+                        this.resources().find(capability)?.change(|cap| {
+                            cap.resource = Resource::MemoryRegion(lower);
+                        });
+                        destination.change(|cap| {
+                            cap.resource = Resource::MemoryRegion(upper);
+                        });
+                        Ok(0)
+                    }
+                    MemoryRegionOp::Untype { offset } => {
+                        let frame = region.frame(offset).ok_or(CapError::FrameOutsideOfRegion)?;
+                        frame.try_into_untyped().map_err(|e| match e {
+                            RetypeError::RefsExist(_) => CapError::ResourceInUse,
+                            RetypeError::InvalidFromState(_) => CapError::InvalidArgument,
+                            RetypeError::OutOfBounds => CapError::FrameOutsideOfRegion,
+                        })?;
+                        Ok(0)
+                    }
+                }
+            }
+            Resource::DeviceRegion(region) => {
+                let operation =
+                    DeviceRegionOp::from_args(args).map_err(|_| CapError::InvalidArgument)?;
+                match operation {
+                    DeviceRegionOp::Split { offset, slot } => {
+                        let (lower, upper) =
+                            region.split(offset).ok_or(CapError::InvalidArgument)?;
+                        let destination = this.resources().find(slot)?;
+                        if !destination.get().resource.is_empty() {
+                            return Err(CapError::ResourceInUse);
+                        }
+                        this.resources().find(capability)?.change(|cap| {
+                            cap.resource = Resource::DeviceRegion(lower);
+                        });
+                        destination.change(|cap| {
+                            cap.resource = Resource::DeviceRegion(upper);
+                        });
+                        Ok(0)
+                    }
+                }
+            }
+            Resource::IoPorts { range, grantee } => {
+                let operation =
+                    IoPortsOp::from_args(args).map_err(|_| CapError::InvalidArgument)?;
+                match operation {
+                    IoPortsOp::Split { offset, slot } => {
+                        // The grant covers the whole range, so it has to be
+                        // revoked first.
+                        if grantee.is_some() {
+                            return Err(CapError::ResourceInUse);
+                        }
+                        let (lower, upper) =
+                            range.split(offset).ok_or(CapError::InvalidArgument)?;
+                        let destination = this.resources().find(slot)?;
+                        if !destination.get().resource.is_empty() {
+                            return Err(CapError::ResourceInUse);
+                        }
+                        this.resources().find(capability)?.change(|cap| {
+                            cap.resource = Resource::IoPorts {
+                                range: lower,
+                                grantee: None,
+                            };
+                        });
+                        destination.change(|cap| {
+                            cap.resource = Resource::IoPorts {
+                                range: upper,
+                                grantee: None,
+                            };
+                        });
+                        Ok(0)
+                    }
+                    IoPortsOp::Grant { thread } => {
+                        let thread: KPtr<Thread> = this.resources().get_resource_as(thread)?;
+                        match grantee {
+                            Some(grantee) if grantee == thread => return Ok(0),
+                            Some(_) => return Err(CapError::ResourceInUse),
+                            None => {}
+                        }
+                        Thread::grant_io_ports(&thread, range)?;
+                        this.resources().find(capability)?.change(|cap| {
+                            cap.resource = Resource::IoPorts {
+                                range,
+                                grantee: Some(thread),
+                            };
+                        });
+                        Ok(0)
+                    }
+                    IoPortsOp::Revoke { thread } => {
+                        let thread: KPtr<Thread> = this.resources().get_resource_as(thread)?;
+                        if grantee.as_ref() != Some(&thread) {
+                            return Err(CapError::NotFound);
+                        }
+                        // The capability is released first so that it can be
+                        // granted again whatever happens next.
+                        this.resources().find(capability)?.change(|cap| {
+                            cap.resource = Resource::IoPorts {
+                                range,
+                                grantee: None,
+                            };
+                        });
+                        Thread::revoke_io_ports(&thread, range)?;
+                        Ok(0)
+                    }
+                }
+            }
+            Resource::IrqControl => {
+                let operation =
+                    IrqControlOp::from_args(args).map_err(|_| CapError::InvalidArgument)?;
+                match operation {
+                    IrqControlOp::Get { line, slot } => {
+                        if !interrupts::is_device_irq(line) {
+                            return Err(CapError::InvalidArgument);
+                        }
+                        let destination = this.resources().find(slot)?;
+                        if !destination.get().resource.is_empty() {
+                            return Err(CapError::ResourceInUse);
+                        }
+                        irq::claim(line)?;
+                        destination.change(|cap| {
+                            cap.resource = Resource::IrqHandler(line);
+                        });
+                        Ok(0)
+                    }
+                }
+            }
+            Resource::IrqHandler(line) => {
+                let operation =
+                    IrqHandlerOp::from_args(args).map_err(|_| CapError::InvalidArgument)?;
+                match operation {
+                    IrqHandlerOp::Bind { notification, bits } => {
+                        let notification: KPtr<Notification> =
+                            this.resources().get_resource_as(notification)?;
+                        irq::bind(line, notification, bits)?;
+                        Ok(0)
+                    }
+                    IrqHandlerOp::Unbind => {
+                        irq::unbind(line)?;
+                        Ok(0)
+                    }
+                    IrqHandlerOp::Wait => irq::wait(line, this),
+                    IrqHandlerOp::Ack => {
+                        irq::acknowledge(line);
+                        Ok(0)
+                    }
+                }
+            }
+            Resource::Pci => {
+                let operation = PciOp::from_args(args).map_err(|_| CapError::InvalidArgument)?;
+                match operation {
+                    PciOp::Function { index, slot } => {
+                        let address = pci::function(index as usize).ok_or(CapError::NotFound)?;
+                        let destination = this.resources().find(slot)?;
+                        if !destination.get().resource.is_empty() {
+                            return Err(CapError::ResourceInUse);
+                        }
+                        destination.change(|cap| {
+                            cap.resource = Resource::PciFunction(address);
+                        });
+                        Ok(address.as_u32() as usize)
+                    }
+                }
+            }
+            Resource::PciFunction(address) => {
+                let operation =
+                    PciFunctionOp::from_args(args).map_err(|_| CapError::InvalidArgument)?;
+                let register = |offset: u16| {
+                    if offset % 4 != 0 || offset >= pci::config_size() {
+                        return Err(CapError::InvalidArgument);
+                    }
+                    Ok(offset)
+                };
+                match operation {
+                    PciFunctionOp::Read { offset } => Ok(address.read(register(offset)?) as usize),
+                    PciFunctionOp::Write { offset, value } => {
+                        if !address.is_writable(register(offset)?) {
+                            return Err(CapError::InvalidArgument);
+                        }
+                        address.write(offset, value);
+                        Ok(0)
+                    }
+                    PciFunctionOp::Bar { index, slot } => {
+                        let destination = this.resources().find(slot)?;
+                        if !destination.get().resource.is_empty() {
+                            return Err(CapError::ResourceInUse);
+                        }
+                        let (resource, size) = match address.bar(index).ok_or(CapError::NotFound)? {
+                            // An unassigned BAR decodes nothing, its region
+                            // would only cover low memory.
+                            Bar::Memory { base: 0, .. } | Bar::Io { base: 0, .. } => {
+                                return Err(CapError::InvalidArgument);
+                            }
+                            Bar::Memory { base, size } => {
+                                let offset = base % FRAME_SIZE;
+                                let frames = (offset + size).div_ceil(FRAME_SIZE);
+                                let base =
+                                    RawFrame::from_start_address(PhysAddr::new(base - offset));
+                                // Regions index frames with 32 bits.
+                                if (base.base().as_u64() / FRAME_SIZE).max(frames) > u32::MAX as u64
+                                {
+                                    return Err(CapError::InvalidArgument);
+                                }
+                                let region = DeviceRegion::new(base, frames as usize);
+                                (Resource::DeviceRegion(region), size as usize)
+                            }
+                            Bar::Io { base, size } => {
+                                let range =
+                                    PortRange::new(base, size).ok_or(CapError::InvalidArgument)?;
+                                let resource = Resource::IoPorts {
+                                    range,
+                                    grantee: None,
+                                };
+                                (resource, size as usize)
+                            }
+                        };
+                        destination.change(|cap| cap.resource = resource);
+                        Ok(size)
+                    }
                 }
             }
-            Resource::PageTable { table: _, flags: _ } => todo!(),
         }
     }
 }
+
+/// Marks entries mapping device memory, which hold no frame references.
+const DEVICE_MAPPING: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Converts the access rights requested by userspace into entry flags.
+fn user_page_flags(flags: PageFlags) -> PageTableFlags {
+    let mut entry = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if flags.contains(PageFlags::WRITABLE) {
+        entry |= PageTableFlags::WRITABLE;
+    }
+    if !flags.contains(PageFlags::EXECUTABLE) {
+        entry |= PageTableFlags::NO_EXECUTE;
+    }
+    entry
+}
+
+/// Takes a user reference on the unit of frames in the `size` bytes starting
+/// at `frame`.
+///
+/// Untyped frames are retyped into user memory. Fails if the frames are
+/// neither untyped nor a user unit of the same size.
+fn reference_user_frames(frame: RawFrame, size: usize) -> Result<UserFrame, CapError> {
+    let frames = size / PAGE_SIZE;
+    frame
+        .try_as_user_unit(frames)
+        .map_err(|_| ())
+        .or_else(|_| frame.try_into_user_unit(frames).map_err(|_| ()))
+        .map_err(|_| CapError::FrameNotUser)
+}
@@ -0,0 +1,98 @@
+//! Synchronous IPC through endpoints.
+//!
+//! An endpoint is a rendezvous point between a server thread waiting on it and
+//! a client calling into it. Calls don't queue: the server must be waiting on
+//! the endpoint (through `ReplyRecv`) for a call to go through. The kernel
+//! switches directly to the other thread on every operation, transferring the
+//! message registers and the long message in the IPC buffer on the way.
+
+use kapi::ops::endpoint::Message;
+use kapi::raw::CapError;
+use sync::cell::AtomicCell;
+
+use crate::arch::interrupts::SyscallCtx;
+use crate::component::Thread;
+use crate::kptr::KPtr;
+
+/// A kernel object enabling synchronous invocations between threads.
+#[repr(align(4096))]
+#[derive(Default)]
+pub struct Endpoint {
+    receiver: AtomicCell<Option<KPtr<Thread>>>,
+}
+
+impl Endpoint {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends the message to the waiting receiver and switches to it.
+    ///
+    /// The caller is resumed once the receiver replies. Only returns on failure.
+    pub fn call(this: KPtr<Self>, caller: KPtr<Thread>, message: Message) -> CapError {
+        let Some(receiver) = this.receiver.replace(None) else {
+            return CapError::WouldBlock;
+        };
+        if let Err(e) = Thread::transfer_message(&caller, &receiver) {
+            this.receiver.set(Some(receiver));
+            return e;
+        }
+        drop(this);
+        caller.mark_calling();
+        receiver.set_reply_to(Some(caller));
+        receiver.deliver(message);
+        // SAFETY: Endpoint operations are only performed from syscalls.
+        let ctx = unsafe { SyscallCtx::current() };
+        Thread::dispatch(receiver, ctx);
+    }
+
+    /// Replies to the thread that last called into `current` and switches to it.
+    ///
+    /// `current` will appear to return successfully once it gets dispatched
+    /// again. Only returns on failure.
+    pub fn reply(current: KPtr<Thread>, message: Message) -> CapError {
+        let Some(caller) = current.take_caller() else {
+            return CapError::InvalidOp;
+        };
+        if let Err(e) = Thread::transfer_message(&current, &caller) {
+            current.set_reply_to(Some(caller));
+            return e;
+        }
+        current.deliver([0; kapi::raw::MESSAGE_REGISTERS]);
+        drop(current);
+        caller.deliver(message);
+        // SAFETY: Endpoint operations are only performed from syscalls.
+        let ctx = unsafe { SyscallCtx::current() };
+        Thread::dispatch(caller, ctx);
+    }
+
+    /// Replies to the thread that last called into `current` and waits on the
+    /// endpoint for the next call. Only returns on failure.
+    ///
+    /// A thread that was activated (as opposed to called into) replies to its
+    /// activator, which is how a server starts waiting on its endpoint.
+    pub fn reply_recv(this: KPtr<Self>, current: KPtr<Thread>, message: Message) -> CapError {
+        let Some(caller) = current.take_caller() else {
+            return CapError::InvalidOp;
+        };
+        // The endpoint is claimed first so that a busy endpoint fails the
+        // whole transfer.
+        if let Some(previous) = this.receiver.replace(Some(current.clone())) {
+            // Someone else is already waiting, undo.
+            this.receiver.set(Some(previous));
+            current.set_reply_to(Some(caller));
+            return CapError::ResourceInUse;
+        }
+        if let Err(e) = Thread::transfer_message(&current, &caller) {
+            this.receiver.set(None);
+            current.set_reply_to(Some(caller));
+            return e;
+        }
+        drop(current);
+        drop(this);
+        caller.deliver(message);
+        // SAFETY: Endpoint operations are only performed from syscalls.
+        let ctx = unsafe { SyscallCtx::current() };
+        Thread::dispatch(caller, ctx);
+    }
+}
@@ -0,0 +1,137 @@
+//! Device interrupts handled by userspace.
+//!
+//! A firing line is masked and routed to its handler: the notification bound
+//! to it, or the thread waiting on it. When that wakes a thread, the kernel
+//! switches to it right away, and the interrupted thread resumes once the woken
+//! one blocks again. The line stays masked until the handler acknowledges it.
+
+use kapi::raw::CapError;
+use sync::cell::AtomicRefCell;
+
+use crate::arch::interrupts::{self, SyscallCtx, IRQ_LINES};
+use crate::component::Thread;
+use crate::kptr::KPtr;
+use crate::notification::Notification;
+
+static LINES: AtomicRefCell<[Line; IRQ_LINES as usize]> =
+    AtomicRefCell::new([const { Line::new() }; IRQ_LINES as usize]);
+
+struct Line {
+    /// Notification signaled with the bits when the line fires.
+    notification: Option<(KPtr<Notification>, u64)>,
+    /// Thread waiting for the line to fire.
+    waiter: Option<KPtr<Thread>>,
+    /// Whether the line fired with nothing to handle it.
+    pending: bool,
+    /// Whether a handler capability exists for the line.
+    claimed: bool,
+}
+
+impl Line {
+    const fn new() -> Self {
+        Self {
+            notification: None,
+            waiter: None,
+            pending: false,
+            claimed: false,
+        }
+    }
+}
+
+pub fn init() {
+    interrupts::set_irq_handler(fired);
+}
+
+fn with_line<T>(line: u8, fun: impl FnOnce(&mut Line) -> T) -> T {
+    let mut lines = LINES.borrow_mut().expect("IRQ lines accessed concurrently");
+    fun(&mut lines[line as usize])
+}
+
+/// Routes the masked `line` to its handler, switching to the woken thread if
+/// there's one.
+fn fired(line: u8) {
+    let next = with_line(line, |line| match &line.notification {
+        Some((notification, bits)) => notification.signal(*bits),
+        None => match line.waiter.take() {
+            Some(waiter) => {
+                waiter.set_return_value(0);
+                Some(waiter)
+            }
+            None => {
+                line.pending = true;
+                None
+            }
+        },
+    });
+    if let Some(next) = next {
+        Thread::preempt(next);
+    }
+}
+
+/// Claims `line` for its handler capability, of which there's only one.
+pub fn claim(line: u8) -> Result<(), CapError> {
+    with_line(line, |line| {
+        if core::mem::replace(&mut line.claimed, true) {
+            return Err(CapError::ResourceInUse);
+        }
+        Ok(())
+    })
+}
+
+/// Masks `line` and forgets its handler, once the handler capability is
+/// dropped.
+pub fn release(line: u8) {
+    interrupts::mask_irq(line);
+    let previous = with_line(line, |line| core::mem::replace(line, Line::new()));
+    drop(previous);
+}
+
+/// Signals `bits` on `notification` whenever `line` fires.
+pub fn bind(line: u8, notification: KPtr<Notification>, bits: u64) -> Result<(), CapError> {
+    with_line(line, |line| {
+        if line.waiter.is_some() {
+            return Err(CapError::ResourceInUse);
+        }
+        line.notification = Some((notification, bits));
+        line.pending = false;
+        Ok(())
+    })
+}
+
+/// Removes the notification bound to `line`.
+pub fn unbind(line: u8) -> Result<(), CapError> {
+    with_line(line, |line| line.notification.take().map(|_| ())).ok_or(CapError::NotFound)
+}
+
+/// Blocks `current` until `line` fires.
+///
+/// Blocking hands control back to the thread that last switched to
+/// `current`, which fails with `WouldBlock` if there isn't one or if it's
+/// still waiting for a reply.
+pub fn wait(line: u8, current: KPtr<Thread>) -> Result<usize, CapError> {
+    let next = with_line(line, |line| {
+        if line.notification.is_some() || line.waiter.is_some() {
+            return Err(CapError::ResourceInUse);
+        }
+        if core::mem::take(&mut line.pending) {
+            return Ok(None);
+        }
+        let Some(next) = current.take_next_on_block() else {
+            return Err(CapError::WouldBlock);
+        };
+        line.waiter = Some(current);
+        Ok(Some(next))
+    })?;
+    let Some(next) = next else {
+        return Ok(0);
+    };
+    next.deliver([0; kapi::raw::MESSAGE_REGISTERS]);
+    // SAFETY: IRQ operations are only performed from syscalls.
+    let ctx = unsafe { SyscallCtx::current() };
+    Thread::dispatch(next, ctx);
+}
+
+/// Unmasks `line` once its handler is done with the device.
+pub fn acknowledge(line: u8) {
+    interrupts::unmask_irq(line);
+}
@@ -84,15 +84,15 @@ impl<T> KPtr<T> {
     }
 
     pub fn try_into_inner(self) -> Option<T> {
+        let this = ManuallyDrop::new(self);
+        let mut inner = None;
         // SAFETY: The frame must be typed as kernel since we have a reference
         // to it.
-        let count = unsafe { KernelFrame::from_raw(self.frame()).drop() };
-        if count == 1 {
+        unsafe { KernelFrame::from_raw(this.frame()) }.release(|| {
             // last one turns off the lights
-            Some(unsafe { self.inner.as_ptr().read() })
-        } else {
-            None
-        }
+            inner = Some(unsafe { this.inner.as_ptr().read() });
+        });
+        inner
     }
 
     pub fn into_raw(self) -> RawFrame {
@@ -125,14 +125,13 @@ impl<T> Clone for KPtr<T> {
 
 impl<T> Drop for KPtr<T> {
     fn drop(&mut self) {
-        let count = unsafe { KernelFrame::from_raw(self.frame()).drop() };
         // last one turns off the lights
-        if count == 1 {
+        unsafe { KernelFrame::from_raw(self.frame()) }.release(|| {
             fence(Ordering::Acquire);
             log::trace!("Last ones! Dropping T");
             unsafe {
                 self.inner.as_ptr().drop_in_place();
             }
-        }
+        });
     }
 }
@@ -23,7 +23,10 @@ pub mod bump_allocator;
 pub mod caps;
 pub mod component;
 pub mod core_local;
+pub mod ipc;
+pub mod irq;
 pub mod kptr;
+pub mod notification;
 pub mod retyping;
 pub mod syscall;
 
@@ -34,7 +37,11 @@ mod serial;
 
 pub type MemoryMap = &'static mut [&'static mut Entry];
 
-pub const UNTYPED_MEMORY_OFFSET: usize = 0x0000_7000_0000_0000;
+#[used]
+static mut MEMORY_MAP: MemoryMapRequest = MemoryMapRequest::new();
+
+/// Top of the boot component's stack.
+pub const BOOT_STACK_TOP: usize = 0x0000_7000_0000_0000;
 
 pub static PMO: AtomicLazyCell<VirtAddr> = AtomicLazyCell::new(|| {
     #[used]
@@ -52,44 +59,140 @@ pub static PMO: AtomicLazyCell<VirtAddr> = AtomicLazyCell::new(|| {
 #[cfg(not(test))]
 #[no_mangle]
 extern "C" fn kmain() -> ! {
+    // The bootloader's stack is reclaimed before the boot component runs.
+    // SAFETY: The bootloader only enters the kernel once.
+    unsafe { arch::enter_boot_stack(boot) }
+}
+
+#[cfg(not(test))]
+extern "sysv64" fn boot() -> ! {
     use arch::bootup::Process;
-    use arch::exec::{ExecCtx, NoopSaver};
-    use arch::paging::RawFrame;
+    use arch::exec::NoopSaver;
+    use arch::paging::{PhysAddr, RawFrame, PAGE_SIZE};
     use bump_allocator::BumpAllocator;
-    use caps::RawCapEntry;
+    use caps::{
+        CapEntryExtension as _, DeviceRegion, MemoryRegion, PageCapFlags, RawCapEntry, Resource,
+    };
     use component::Thread;
+    use kapi::ops::cap_table::BOOT_CAP_TABLE_CAP;
+    use kapi::ops::clock::BOOT_CLOCK_CAP;
+    use kapi::ops::device_region::BOOT_DEVICE_CAP;
+    use kapi::ops::io_ports::BOOT_IO_PORTS_CAP;
+    use kapi::ops::irq::BOOT_IRQ_CONTROL_CAP;
+    use kapi::ops::memory_region::BOOT_MEMORY_CAP;
+    use kapi::ops::page_table::BOOT_PAGE_TABLE_CAP;
+    use kapi::ops::pci::BOOT_PCI_CAP;
     use kptr::KPtr;
 
     init();
 
-    let booter: ExecCtx = {
+    let (booter, l4_table) = {
         let proc = include_bytes_aligned::include_bytes_aligned!(16, "../../../.build/booter");
         log::info!("Loading user process");
-        let process =
-            Process::load(proc, 10, UNTYPED_MEMORY_OFFSET, RawFrame::memory_limit()).unwrap();
-        process.into_exec()
+        let process = Process::load(proc, 10, BOOT_STACK_TOP).unwrap();
+        let l4_table = process.l4_table.clone();
+        (process.into_exec(), l4_table)
     };
     let mut fallocator = BumpAllocator::new();
     let resources = {
         let frame = fallocator.alloc_untyped_frame().unwrap();
         KPtr::new(frame, RawCapEntry::default()).unwrap()
     };
+    resources
+        .clone()
+        .find(BOOT_CAP_TABLE_CAP)
+        .unwrap()
+        .change(|cap| cap.resource = Resource::CapEntry(resources.clone()));
+    resources
+        .clone()
+        .find(BOOT_PAGE_TABLE_CAP)
+        .unwrap()
+        .change(|cap| {
+            cap.resource = Resource::PageTable {
+                table: l4_table,
+                flags: PageCapFlags::new(4),
+            }
+        });
+    resources
+        .clone()
+        .find(BOOT_CLOCK_CAP)
+        .unwrap()
+        .change(|cap| cap.resource = Resource::Clock);
+    let memory = MemoryRegion::new(
+        RawFrame::from_start_address(PhysAddr::new(0)),
+        RawFrame::memory_limit() / PAGE_SIZE,
+    );
+    resources
+        .clone()
+        .find(BOOT_MEMORY_CAP)
+        .unwrap()
+        .change(|cap| cap.resource = Resource::MemoryRegion(memory));
+    // The device region covers the whole physical address space, only the
+    // frames typed as device memory can be mapped through it.
+    let physical_frames = (1u64 << arch::paging::physical_address_bits()) / PAGE_SIZE as u64;
+    let devices = DeviceRegion::new(
+        RawFrame::from_start_address(PhysAddr::new(0)),
+        physical_frames.min(u32::MAX as u64) as usize,
+    );
+    resources
+        .clone()
+        .find(BOOT_DEVICE_CAP)
+        .unwrap()
+        .change(|cap| cap.resource = Resource::DeviceRegion(devices));
+    resources
+        .clone()
+        .find(BOOT_IO_PORTS_CAP)
+        .unwrap()
+        .change(|cap| {
+            cap.resource = Resource::IoPorts {
+                range: arch::io_ports::PortRange::all(),
+                grantee: None,
+            }
+        });
+    resources
+        .clone()
+        .find(BOOT_IRQ_CONTROL_CAP)
+        .unwrap()
+        .change(|cap| cap.resource = Resource::IrqControl);
+    resources
+        .clone()
+        .find(BOOT_PCI_CAP)
+        .unwrap()
+        .change(|cap| cap.resource = Resource::Pci);
     let thread = {
         let frame = fallocator.alloc_untyped_frame().unwrap();
         KPtr::new(frame, Thread::new_with_ctx(booter, resources)).unwrap()
     };
 
+    // SAFETY: The kernel runs on its own boot stack.
+    unsafe { reclaim_bootloader_memory() };
     log::info!("Jumping to boot component");
     Thread::dispatch(thread, NoopSaver::new());
 }
 
+/// Returns the memory the bootloader used back to the untyped pool.
+///
+/// # Safety
+///
+/// The kernel must be done with the bootloader responses and must have left
+/// the bootloader's stack.
+pub unsafe fn reclaim_bootloader_memory() {
+    // SAFETY: The memory map is only read once the retype table is set up.
+    let memory_map = unsafe {
+        MEMORY_MAP
+            .get_response()
+            .expect("Missing memory map from Limine")
+            .entries()
+    };
+    // SAFETY: Guaranteed by the caller.
+    let frames = unsafe { retyping::reclaim_bootloader_memory(memory_map) };
+    log::info!("Reclaimed {frames} frames of bootloader memory");
+}
+
 pub fn init() {
     #[used]
     static BASE_REVISION: BaseRevision = BaseRevision::with_revision(1);
 
-    #[used]
-    static mut MEMORY_MAP: MemoryMapRequest = MemoryMapRequest::new();
-
     #[used]
     static STACK_SIZE: StackSizeRequest = StackSizeRequest::new().with_size(0x32000);
     interrupts::disable();
@@ -118,8 +221,12 @@ pub fn init() {
     RetypeTable::new(memory_map).unwrap().init().unwrap();
     log::info!("Initialized the retype table");
 
+    arch::late_init();
+
     component::init();
     log::info!("Initialized component system");
+
+    irq::init();
 }
 
 #[cfg(all(target_os = "none", not(test)))]
@@ -0,0 +1,92 @@
+//! Asynchronous notifications.
+//!
+//! A notification is a word of bits that any holder of the capability can set
+//! without blocking. A thread can consume the pending bits by polling, or by
+//! waiting until there are any. Signaling a notification with a waiting thread
+//! switches to it directly, and control comes back to the signaler once the
+//! woken thread blocks again.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use kapi::ops::notification::NOTIFICATION_MASK;
+use kapi::raw::CapError;
+use sync::cell::AtomicCell;
+
+use crate::arch::interrupts::SyscallCtx;
+use crate::component::Thread;
+use crate::kptr::KPtr;
+
+/// A kernel object for signaling threads without blocking.
+#[repr(align(4096))]
+#[derive(Default)]
+pub struct Notification {
+    word: AtomicU64,
+    waiter: AtomicCell<Option<KPtr<Thread>>>,
+}
+
+impl Notification {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the bits on the notification.
+    ///
+    /// If a thread was waiting, the pending bits are consumed on its behalf and
+    /// the thread is returned so that the caller can switch to it.
+    pub fn signal(&self, bits: u64) -> Option<KPtr<Thread>> {
+        // The word is returned from syscalls, where the top bit marks errors.
+        self.word
+            .fetch_or(bits & NOTIFICATION_MASK, Ordering::Release);
+        let waiter = self.waiter.replace(None)?;
+        waiter.set_return_value(self.poll() as usize);
+        Some(waiter)
+    }
+
+    /// Consumes the pending bits without blocking.
+    pub fn poll(&self) -> u64 {
+        self.word.swap(0, Ordering::Acquire)
+    }
+
+    /// Signals the notification and switches to the waiting thread, if any.
+    ///
+    /// `current` will appear to return successfully once it gets dispatched
+    /// again.
+    pub fn signal_from(this: KPtr<Self>, current: KPtr<Thread>, bits: u64) -> usize {
+        let Some(waiter) = this.signal(bits) else {
+            return 0;
+        };
+        drop(this);
+        current.set_return_value(0);
+        waiter.set_reply_to(Some(current));
+        // SAFETY: Notification operations are only performed from syscalls.
+        let ctx = unsafe { SyscallCtx::current() };
+        Thread::dispatch(waiter, ctx);
+    }
+
+    /// Consumes the pending bits, blocking until there are any.
+    ///
+    /// Blocking hands control back to the thread that last switched to
+    /// `current`, which fails with `WouldBlock` if there isn't one or if it's
+    /// still waiting for a reply. Only one thread may wait on a notification
+    /// at a time.
+    pub fn wait(this: KPtr<Self>, current: KPtr<Thread>) -> Result<usize, CapError> {
+        let bits = this.poll();
+        if bits != 0 {
+            return Ok(bits as usize);
+        }
+        let Some(next) = current.take_next_on_block() else {
+            return Err(CapError::WouldBlock);
+        };
+        if let Some(previous) = this.waiter.replace(Some(current)) {
+            // Someone else is already waiting, undo.
+            let current = this.waiter.replace(Some(previous)).unwrap();
+            current.set_reply_to(Some(next));
+            return Err(CapError::ResourceInUse);
+        }
+        drop(this);
+        next.deliver([0; kapi::raw::MESSAGE_REGISTERS]);
+        // SAFETY: Notification operations are only performed from syscalls.
+        let ctx = unsafe { SyscallCtx::current() };
+        Thread::dispatch(next, ctx);
+    }
+}
@@ -1,16 +1,20 @@
 use core::mem::{ManuallyDrop, MaybeUninit};
 use core::sync::atomic::{AtomicU16, Ordering};
 
-use limine::memory_map::EntryType;
+use limine::memory_map::{Entry, EntryType};
 use sync::cell::AtomicOnceCell;
 
-use crate::arch::paging::page_table::AnyPageTable;
-use crate::arch::paging::{RawFrame, FRAME_SIZE, PAGE_SIZE};
+use crate::arch::paging::page_table::{AnyPageTable, PageTableLevel};
+use crate::arch::paging::{PhysAddr, RawFrame, FRAME_SIZE, PAGE_SIZE};
 use crate::retyping::bump_alloc::BumpAllocator;
 use crate::MemoryMap;
 
 static RETYPE_TABLE: AtomicOnceCell<RetypeTable> = AtomicOnceCell::new();
 
+/// The table covers at least the 32-bit physical address space, so that the
+/// devices below 4GiB are tracked even when there's less memory.
+const MIN_PHYSICAL_TOP: u64 = 1 << 32;
+
 pub struct RetypeTable {
     retype_map: &'static mut [RetypeEntry],
 }
@@ -19,7 +23,7 @@ impl RetypeTable {
     pub fn new(memory_map: MemoryMap) -> Option<Self> {
         let physical_top = {
             let last = memory_map.iter().last()?;
-            last.base + last.length
+            (last.base + last.length).max(MIN_PHYSICAL_TOP)
         };
         assert!(physical_top % FRAME_SIZE == 0);
         let number_frames = (physical_top / FRAME_SIZE) as usize;
@@ -38,8 +42,9 @@ impl RetypeTable {
             unsafe { core::slice::from_raw_parts_mut(start_addr, number_frames) }
         };
 
+        // Anything the memory map doesn't cover is device memory.
         for entry in retype_map.iter_mut() {
-            entry.write(RetypeEntry::unavailable());
+            entry.write(RetypeEntry::device());
         }
         // SAFETY: Initialized in earlier loop
         let retype_map: &mut [RetypeEntry] = unsafe { core::mem::transmute(retype_map) };
@@ -56,6 +61,7 @@ impl RetypeTable {
                     EntryType::BOOTLOADER_RECLAIMABLE | EntryType::KERNEL_AND_MODULES => {
                         RetypeEntry::kernel(1)
                     }
+                    EntryType::FRAMEBUFFER => RetypeEntry::device(),
                     _ => RetypeEntry::unavailable(),
                 };
                 *slot = retype_entry;
@@ -73,6 +79,54 @@ impl RetypeTable {
     }
 }
 
+/// Turns the memory the bootloader marked as reclaimable into untyped memory.
+///
+/// The kernel's page tables were set up by the bootloader and are shared with
+/// every address space, so they stay typed as kernel. Returns the number of
+/// reclaimed frames.
+///
+/// # Safety
+///
+/// Everything the kernel needs from the bootloader responses must have been
+/// copied out already. The bootloader's stack lives in this memory too, so the
+/// kernel must have left it.
+pub unsafe fn reclaim_bootloader_memory(memory_map: &[&Entry]) -> usize {
+    let l4_table = AnyPageTable::current();
+    // Tables are pinned with an extra reference so they can't be reclaimed.
+    let adjust_tables = |fun: fn(&RetypeEntry)| {
+        l4_table.for_each_table(PageTableLevel::top(), &mut |frame| {
+            if let Ok(entry) = frame.retype_entry() {
+                fun(entry);
+            }
+        });
+    };
+    adjust_tables(|entry| {
+        entry.increment().unwrap();
+    });
+
+    let mut reclaimed = 0;
+    let reclaimable = memory_map
+        .iter()
+        .filter(|entry| entry.entry_type == EntryType::BOOTLOADER_RECLAIMABLE);
+    for entry in reclaimable {
+        for i in 0..entry.length / FRAME_SIZE {
+            let frame = RawFrame::from_start_address(PhysAddr::new(entry.base + i * FRAME_SIZE));
+            let retyped = frame
+                .retype_entry()
+                .unwrap()
+                .retype(State::Kernel, State::Untyped, 1, 0);
+            if retyped.is_ok() {
+                reclaimed += 1;
+            }
+        }
+    }
+
+    adjust_tables(|entry| {
+        entry.decrement().unwrap();
+    });
+    reclaimed
+}
+
 #[derive(Debug)]
 pub struct OutOfBounds;
 
@@ -117,6 +171,23 @@ impl RawFrame {
         nframes * FRAME_SIZE as usize
     }
 
+    /// Returns whether the frame is device memory, which includes everything
+    /// above the memory map.
+    pub fn is_device(&self) -> bool {
+        match self.retype_entry() {
+            Ok(entry) => entry.get().0 == State::Device,
+            Err(OutOfBounds) => true,
+        }
+    }
+
+    /// Takes the device frame for the kernel's own use, so that it can't be
+    /// mapped through a device region anymore.
+    pub fn reserve_device(&self) -> Result<(), RetypeError> {
+        self.retype_entry()?
+            .retype(State::Device, State::Unavailable, 0, 0)
+            .map_err(|(state, _)| RetypeError::InvalidFromState(state))
+    }
+
     fn retype_entry(&self) -> Result<&'static RetypeEntry, OutOfBounds> {
         let index = (self.addr().as_u64() / FRAME_SIZE) as usize;
         RETYPE_TABLE
@@ -132,7 +203,8 @@ impl RawFrame {
         self.retype_entry()?
             .get_as_and_increment(State::User)
             .map_err(|(state, value)| {
-                if !matches!(state, State::User) {
+                // Pinned frames can only be referenced through their unit.
+                if !matches!(state, State::User) || value == RetypeEntry::PINNED {
                     AsTypeError::NotExpectedState(state)
                 } else {
                     debug_assert!(value == RetypeEntry::MAX_REF_COUNT);
@@ -189,44 +261,198 @@ impl RawFrame {
     }
 
     pub fn try_into_user(self) -> Result<UserFrame, RetypeError> {
-        self.retype_entry()?
-            .retype(State::Untyped, State::User, 0, 1)
-            .map_err(|(state, _count)| RetypeError::InvalidFromState(state))?;
+        self.claim(State::Untyped, State::User, 1)?;
         Ok(UserFrame(self))
     }
 
+    /// Retypes the `frames` untyped frames starting at this one into user
+    /// memory as a single unit (e.g. a huge page).
+    ///
+    /// The unit is referenced through its first frame. The rest of the frames
+    /// are pinned so that they can't be retyped or referenced on their own.
+    pub fn try_into_user_unit(self, frames: usize) -> Result<UserFrame, RetypeError> {
+        self.nth(frames - 1).retype_entry()?;
+        for i in 1..frames {
+            let pinned = self
+                .nth(i)
+                .claim(State::Untyped, State::User, RetypeEntry::PINNED);
+            if let Err(e) = pinned {
+                self.unpin(1..i);
+                return Err(e);
+            }
+        }
+        match self.claim(State::Untyped, State::User, 1) {
+            Ok(()) => Ok(UserFrame(self)),
+            Err(e) => {
+                self.unpin(1..frames);
+                Err(e)
+            }
+        }
+    }
+
+    /// References the unit of `frames` user frames starting at this one.
+    pub fn try_as_user_unit(self, frames: usize) -> Result<UserFrame, AsTypeError> {
+        self.nth(frames - 1).retype_entry()?;
+        let head = self.try_as_user()?;
+        for i in 1..frames {
+            let (state, count) = self.nth(i).retype_entry()?.get();
+            if state != State::User || count != RetypeEntry::PINNED {
+                return Err(AsTypeError::NotExpectedState(state));
+            }
+        }
+        Ok(head)
+    }
+
+    fn nth(self, i: usize) -> RawFrame {
+        RawFrame::from_start_address(PhysAddr::new(self.base().as_u64() + i as u64 * FRAME_SIZE))
+    }
+
+    /// Returns the pinned frames in the range back to the untyped state.
+    fn unpin(self, range: core::ops::Range<usize>) {
+        for i in range {
+            self.nth(i)
+                .retype_entry()
+                .unwrap()
+                .retype(State::User, State::Untyped, RetypeEntry::PINNED, 0)
+                .unwrap();
+        }
+    }
+
+    /// Claims an unused frame for a new kernel object.
+    ///
+    /// The frame is either untyped or was retyped into an unreferenced kernel
+    /// frame beforehand.
     pub fn try_into_kernel(self) -> Result<KernelFrame, RetypeError> {
-        self.retype_entry()?
-            .retype(State::Untyped, State::Kernel, 0, 1)
-            .map_err(|(state, _count)| RetypeError::InvalidFromState(state))?;
+        self.retype_entry()?;
+        self.claim(State::Untyped, State::Kernel, 1)
+            .or_else(|_| self.claim(State::Kernel, State::Kernel, 1))?;
         Ok(KernelFrame(self))
     }
 
-    fn try_into_untyped_from(self, from: State) -> Result<RawFrame, RetypeError> {
-        assert!(matches!(from, State::User | State::Kernel));
+    /// Retypes an untyped frame into an unreferenced user or kernel frame.
+    pub fn try_retype(self, to: State) -> Result<(), RetypeError> {
+        assert!(matches!(to, State::User | State::Kernel));
+        self.retype_entry()?;
+        self.claim(State::Untyped, to, 0)
+    }
+
+    /// Moves an unreferenced frame from `from` into `to` with `count`
+    /// references, zeroing it on the way.
+    ///
+    /// The frame is reserved while it's scrubbed so that nothing can reference
+    /// it before it's clean.
+    fn claim(self, from: State, to: State, count: u16) -> Result<(), RetypeError> {
         let entry = self.retype_entry()?;
+        entry
+            .retype(from, State::Unavailable, 0, RetypeEntry::RESERVED)
+            .map_err(|(state, _count)| RetypeError::InvalidFromState(state))?;
+        self.scrub();
+        entry
+            .retype(State::Unavailable, to, RetypeEntry::RESERVED, count)
+            .unwrap();
+        Ok(())
+    }
 
-        match entry.retype(from, State::Untyped, 0, 0) {
-            Ok(()) => Ok(self),
-            Err((State::Unavailable, refs)) => {
-                debug_assert_eq!(refs, 0);
-                Err(RetypeError::InvalidFromState(State::Unavailable))
+    /// Drops a reference to a frame in `state`, returning the previous count.
+    ///
+    /// The last reference reserves the frame while `last` runs and, with the
+    /// `KERNEL_SCRUB` feature, while the frame (and the rest of its unit) is
+    /// zeroed.
+    fn release(self, state: State, last: impl FnOnce()) -> Result<u16, OutOfBounds> {
+        let entry = self.retype_entry()?;
+        loop {
+            let (current, count) = entry.get();
+            assert!(
+                current == state && count > 0,
+                "Releasing an unreferenced frame"
+            );
+            if count > 1 {
+                if entry.retype(state, state, count, count - 1).is_ok() {
+                    return Ok(count);
+                }
+            } else if entry
+                .retype(state, State::Unavailable, 1, RetypeEntry::RESERVED)
+                .is_ok()
+            {
+                break;
             }
-            Err((s, refs)) if s == from => {
-                debug_assert_ne!(refs, 0);
-                Err(RetypeError::RefsExist(refs))
+        }
+        last();
+        if cfg!(feature = "KERNEL_SCRUB") {
+            self.scrub();
+            // The pinned frames following the first frame of a unit belong to it.
+            let mut tail = self.nth(1);
+            while let Ok((State::User, RetypeEntry::PINNED)) =
+                tail.retype_entry().map(RetypeEntry::get)
+            {
+                tail.scrub();
+                tail = tail.nth(1);
             }
-            Err((other_state, _refs)) => Err(RetypeError::InvalidFromState(other_state)),
+        }
+        entry
+            .retype(State::Unavailable, state, RetypeEntry::RESERVED, 0)
+            .unwrap();
+        Ok(1)
+    }
+
+    fn scrub(self) {
+        let page: *mut u8 = self.base().to_virtual().as_mut_ptr();
+        // SAFETY: Callers make sure nothing else can access the frame.
+        unsafe {
+            core::ptr::write_bytes(page, 0, PAGE_SIZE);
         }
     }
 
+    /// Retypes an unreferenced user or kernel frame back into untyped memory,
+    /// scrubbing its contents.
+    ///
+    /// Retyping the first frame of a user unit retypes the whole unit.
     pub fn try_into_untyped(self) -> Result<RawFrame, RetypeError> {
-        if self.try_into_untyped_from(State::User).is_ok() {
-            return Ok(self);
+        let state = self.reserve(State::User).or_else(|e| match e {
+            RetypeError::InvalidFromState(_) => self.reserve(State::Kernel),
+            e => Err(e),
+        })?;
+        self.release_scrubbed();
+        if state == State::User {
+            let mut tail = self.nth(1);
+            while tail.reserve_pinned().is_ok() {
+                tail.release_scrubbed();
+                tail = tail.nth(1);
+            }
         }
-        self.try_into_untyped_from(State::Kernel)?;
         Ok(self)
     }
+
+    /// Takes an unreferenced frame out of `from` so that it can't be referenced
+    /// or retyped while it's being scrubbed.
+    fn reserve(self, from: State) -> Result<State, RetypeError> {
+        let entry = self.retype_entry()?;
+        match entry.retype(from, State::Unavailable, 0, RetypeEntry::RESERVED) {
+            Ok(()) => Ok(from),
+            Err((state, refs)) if state == from => Err(RetypeError::RefsExist(refs)),
+            Err((state, _refs)) => Err(RetypeError::InvalidFromState(state)),
+        }
+    }
+
+    fn reserve_pinned(self) -> Result<(), RetypeError> {
+        self.retype_entry()?
+            .retype(
+                State::User,
+                State::Unavailable,
+                RetypeEntry::PINNED,
+                RetypeEntry::RESERVED,
+            )
+            .map_err(|(state, _refs)| RetypeError::InvalidFromState(state))
+    }
+
+    /// Zeroes a reserved frame and hands it back as untyped memory.
+    fn release_scrubbed(self) {
+        self.scrub();
+        self.retype_entry()
+            .unwrap()
+            .retype(State::Unavailable, State::Untyped, RetypeEntry::RESERVED, 0)
+            .unwrap();
+    }
 }
 
 impl UserFrame {
@@ -243,13 +469,25 @@ impl UserFrame {
         ManuallyDrop::new(self).0
     }
 
+    /// Builds back a user frame from the raw frame
+    ///
+    /// # Safety
+    ///
+    /// The frame must have been created with `into_raw`.
+    pub unsafe fn from_raw(frame: RawFrame) -> Self {
+        Self(frame)
+    }
+
     pub fn try_clone(&self) -> Option<Self> {
         self.0.retype_entry().unwrap().increment().ok()?;
         Some(Self(self.frame()))
     }
 
     pub fn drop(self) -> u16 {
-        self.entry().decrement().unwrap()
+        ManuallyDrop::new(self)
+            .0
+            .release(State::User, || ())
+            .expect("User frames are in the retype table")
     }
 }
 
@@ -286,21 +524,34 @@ impl KernelFrame {
     }
 
     pub fn drop(self) -> u16 {
-        self.entry().decrement().unwrap()
+        self.release(|| ())
+    }
+
+    /// Drops the reference, running `last` before the frame can be reused if
+    /// it was the last one. Returns the previous count.
+    pub fn release(self, last: impl FnOnce()) -> u16 {
+        ManuallyDrop::new(self)
+            .0
+            .release(State::Kernel, last)
+            .expect("Kernel frames are in the retype table")
     }
 }
 
 impl Drop for KernelFrame {
     fn drop(&mut self) {
         log::trace!("Dropping {self:?}");
-        self.entry().decrement().unwrap();
+        self.0
+            .release(State::Kernel, || ())
+            .expect("Kernel frames are in the retype table");
     }
 }
 
 impl Drop for UserFrame {
     fn drop(&mut self) {
         log::trace!("Dropping {self:?}");
-        self.entry().decrement().unwrap();
+        self.0
+            .release(State::User, || ())
+            .expect("User frames are in the retype table");
     }
 }
 
@@ -317,6 +568,7 @@ impl State {
             1 => Ok(State::Untyped),
             2 => Ok(State::User),
             3 => Ok(State::Kernel),
+            4 => Ok(State::Device),
             _ => Err(Invalid),
         }
     }
@@ -324,14 +576,21 @@ impl State {
 
 #[allow(unused)]
 impl RetypeEntry {
-    const STATE_BITS: u16 = 2;
+    const STATE_BITS: u16 = 3;
     const COUNTER_BITS: u16 = 16 - Self::STATE_BITS;
-    pub const MAX_REF_COUNT: u16 = (1 << Self::COUNTER_BITS) - 1;
+    const COUNTER_MAX: u16 = (1 << Self::COUNTER_BITS) - 1;
+    /// Highest number of references, one short of the counter's range so
+    /// that referenced frames are never taken for pinned ones.
+    pub const MAX_REF_COUNT: u16 = Self::COUNTER_MAX - 1;
+    /// Count of the frames that are part of a larger unit.
+    const PINNED: u16 = Self::COUNTER_MAX;
+    /// Count of the unavailable frames being retyped back into untyped memory.
+    const RESERVED: u16 = 1;
 
     fn value_for(state: State, counter: u16) -> u16 {
-        assert!(counter <= Self::MAX_REF_COUNT);
+        assert!(counter <= Self::COUNTER_MAX);
 
-        ((state as u8 as u16) << Self::COUNTER_BITS) + counter % Self::MAX_REF_COUNT
+        ((state as u8 as u16) << Self::COUNTER_BITS) + counter
     }
 
     const fn value_into(value: u16) -> (State, u16) {
@@ -351,6 +610,10 @@ impl RetypeEntry {
         Self(AtomicU16::new(Self::value_for(State::Untyped, 0)))
     }
 
+    pub fn device() -> Self {
+        Self(AtomicU16::new(Self::value_for(State::Device, 0)))
+    }
+
     pub fn kernel(ref_count: u16) -> Self {
         Self(AtomicU16::new(Self::value_for(State::Kernel, ref_count)))
     }
@@ -359,7 +622,7 @@ impl RetypeEntry {
         self.0
             .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |value| {
                 let (_, counter) = Self::value_into(value);
-                if counter == Self::MAX_REF_COUNT {
+                if counter >= Self::MAX_REF_COUNT {
                     None
                 } else {
                     Some(value + 1)
@@ -430,6 +693,8 @@ pub enum State {
     Untyped = 1,
     User = 2,
     Kernel = 3,
+    /// Device memory (MMIO), which is never retyped.
+    Device = 4,
 }
 
 mod bump_alloc {
@@ -572,3 +837,110 @@ mod bump_alloc {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn retyping_scrubs_untyped_frames() {
+        let frame = crate::bump_allocator::BumpAllocator::new()
+            .alloc_untyped_frame()
+            .unwrap();
+        let page: *mut u8 = frame.base().to_virtual().as_mut_ptr();
+        // SAFETY: The frame is untyped and unreferenced.
+        unsafe { page.write_bytes(0xAA, PAGE_SIZE) };
+
+        let user = frame.try_into_user().unwrap();
+        // SAFETY: The frame is referenced by this test only.
+        let bytes = unsafe { core::slice::from_raw_parts(page, PAGE_SIZE) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+        assert_eq!(user.drop(), 1);
+        frame.try_into_untyped().unwrap();
+    }
+
+    #[test_case]
+    fn untyping_scrubs_unreferenced_frames() {
+        let frame = crate::bump_allocator::BumpAllocator::new()
+            .alloc_untyped_frame()
+            .unwrap();
+        let user = frame.try_into_user().unwrap();
+        let page: *mut u8 = frame.base().to_virtual().as_mut_ptr();
+        // SAFETY: The frame was just allocated.
+        unsafe { page.write_bytes(0xAA, PAGE_SIZE) };
+        assert!(matches!(
+            frame.try_into_untyped(),
+            Err(RetypeError::RefsExist(1))
+        ));
+
+        drop(user);
+        frame.try_into_untyped().unwrap();
+        assert_eq!(frame.retype_entry().unwrap().get(), (State::Untyped, 0));
+        // SAFETY: The frame is untyped and unreferenced.
+        let bytes = unsafe { core::slice::from_raw_parts(page, PAGE_SIZE) };
+        assert!(bytes.iter().all(|&byte| byte == 0));
+    }
+
+    #[test_case]
+    fn retyping_past_the_table_fails() {
+        let frames = RETYPE_TABLE.get().unwrap().retype_map.len() as u64;
+        let frame = RawFrame::from_start_address(PhysAddr::new(frames * FRAME_SIZE));
+        assert!(matches!(
+            frame.try_into_user(),
+            Err(RetypeError::OutOfBounds)
+        ));
+        assert!(matches!(
+            frame.try_into_kernel(),
+            Err(RetypeError::OutOfBounds)
+        ));
+    }
+
+    #[test_case]
+    fn frames_past_the_table_are_device() {
+        // 64-bit BARs can sit above the memory map.
+        let frames = RETYPE_TABLE.get().unwrap().retype_map.len() as u64;
+        let frame = RawFrame::from_start_address(PhysAddr::new(frames * FRAME_SIZE));
+        assert!(frame.is_device());
+    }
+
+    /// Finds `frames` consecutive untyped frames.
+    fn untyped_run(frames: usize) -> RawFrame {
+        let mut start = crate::bump_allocator::BumpAllocator::new()
+            .alloc_untyped_frame()
+            .unwrap();
+        loop {
+            match (0..frames).find(|&i| start.nth(i).try_as_untyped().is_err()) {
+                None => return start,
+                Some(i) => start = start.nth(i + 1),
+            }
+        }
+    }
+
+    #[test_case]
+    fn untyping_a_unit_spares_a_saturated_neighbour() {
+        let unit = untyped_run(3);
+        let neighbour = unit.nth(2);
+        let head = unit.try_into_user_unit(2).unwrap();
+        // Map the neighbour as many times as it can be.
+        let user = neighbour.try_into_user().unwrap();
+        while let Ok(mapping) = neighbour.try_as_user() {
+            core::mem::forget(mapping);
+        }
+        let entry = neighbour.retype_entry().unwrap();
+        assert_eq!(entry.get(), (State::User, RetypeEntry::MAX_REF_COUNT));
+
+        drop(head);
+        unit.try_into_untyped().unwrap();
+        assert_eq!(
+            unit.nth(1).retype_entry().unwrap().get(),
+            (State::Untyped, 0)
+        );
+        assert_eq!(entry.get(), (State::User, RetypeEntry::MAX_REF_COUNT));
+
+        while entry.get().1 > 1 {
+            entry.decrement().unwrap();
+        }
+        drop(user);
+        neighbour.try_into_untyped().unwrap();
+    }
+}
@@ -29,6 +29,15 @@ pub fn _print(args: ::core::fmt::Arguments) {
     }
 }
 
+/// Writes raw bytes to the serial port.
+pub fn write_bytes(bytes: &[u8]) {
+    for &byte in bytes {
+        unsafe {
+            SERIAL.send(byte);
+        }
+    }
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! sprint {
@@ -1,8 +1,18 @@
-use kapi::raw::{CapError, CapId, SyscallArgs};
+use kapi::raw::{CapError, CapId, SyscallArgs, DEBUG_PRINT_CAP};
 
+use crate::arch::paging::VirtAddr;
+use crate::arch::user;
 use crate::component::Thread;
+use crate::serial;
 
 pub extern "sysv64" fn handle(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> isize {
+    user::forbid_access();
+    if a == DEBUG_PRINT_CAP {
+        return match debug_print(c, d) {
+            Ok(()) => 0,
+            Err(e) => e.to_errno(),
+        };
+    }
     let thread = Thread::current().unwrap();
 
     let Ok(capability) = u32::try_from(a) else {
@@ -10,8 +20,30 @@ pub extern "sysv64" fn handle(a: usize, b: usize, c: usize, d: usize, e: usize,
     };
     let capability = CapId::from(capability);
     let args = SyscallArgs::new(b, c, d, e, f);
-    match thread.exercise_cap(capability, args) {
-        Ok(result) => result.try_into().unwrap(),
+    let result = match Thread::exercise_cap(thread, capability, args) {
+        // The sign bit is reserved for errors, no operation returns it.
+        Ok(result) => isize::try_from(result).unwrap_or(CapError::Internal.to_errno()),
         Err(e) => e.to_errno(),
+    };
+    // The thread may have changed its own top-level entries.
+    #[cfg(feature = "KPTI")]
+    crate::arch::paging::kpti::sync_user_view();
+    result
+}
+
+/// Prints `len` bytes of user memory starting at `addr` to the serial port.
+fn debug_print(addr: usize, len: usize) -> Result<(), CapError> {
+    let mut addr = VirtAddr::try_new(addr).map_err(|_| CapError::InvalidUserAddress)?;
+    let mut buf = [0; 128];
+    let mut left = len;
+    while left > 0 {
+        let size = left.min(buf.len());
+        let chunk = &mut buf[..size];
+        user::copy_from_user(chunk, addr)?;
+        serial::write_bytes(chunk);
+        left -= chunk.len();
+        addr = VirtAddr::try_new(addr.as_usize() + chunk.len())
+            .map_err(|_| CapError::InvalidUserAddress)?;
     }
+    Ok(())
 }
@@ -33,6 +33,11 @@ impl<T> AtomicCell<T> {
         self.spin_lock(|inner| core::mem::replace(inner, value))
     }
 
+    /// Runs `fun` with exclusive access to the inner value.
+    pub fn with<U, F: FnOnce(&mut T) -> U>(&self, fun: F) -> U {
+        self.spin_lock(fun)
+    }
+
     #[inline(always)]
     fn spin_lock<U, F: FnOnce(&mut T) -> U>(&self, fun: F) -> U {
         self.lock();
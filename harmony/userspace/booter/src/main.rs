@@ -1,18 +1,149 @@
 #![no_std]
 #![no_main]
 
-use librs::kapi::raw::raw_syscall;
+use librs::kapi::ops::cap_table::{CapTableOp, ConstructArgs, BOOT_CAP_TABLE_CAP};
+use librs::kapi::ops::component::ComponentOp;
+use librs::kapi::ops::endpoint::EndpointOp;
+use librs::kapi::ops::memory_region::BOOT_MEMORY_CAP;
+use librs::kapi::ops::page_table::BOOT_PAGE_TABLE_CAP;
+use librs::kapi::ops::thread::ThreadOp;
+use librs::kapi::ops::SyscallOp as _;
+use librs::kapi::raw::{raw_syscall, CapError, CapId};
+use librs::println;
+
+/// Number of slots in a capability table node.
+const SLOTS: usize = 128;
+/// First slot left empty by the kernel.
+const ENDPOINT_SLOT: usize = 8;
+const SERVER_SLOT: usize = 9;
+const COMPONENT_SLOT: usize = 10;
+
+const ENDPOINT_CAP: CapId = CapId::new(ENDPOINT_SLOT as u32);
+const SERVER_CAP: CapId = CapId::new(SERVER_SLOT as u32);
+const COMPONENT_CAP: CapId = CapId::new(COMPONENT_SLOT as u32);
+
+const SERVER_STACK_SIZE: usize = 4 * 4096;
+
+/// Stack of the echo server thread.
+#[repr(align(16))]
+struct Stack([u8; SERVER_STACK_SIZE]);
+static mut SERVER_STACK: Stack = Stack([0; SERVER_STACK_SIZE]);
+/// Stack of the invocations of the doubling component.
+static mut COMPONENT_STACK: Stack = Stack([0; SERVER_STACK_SIZE]);
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
-    use librs::println;
     let _ = println!("{}", info);
     loop {}
 }
 
 #[no_mangle]
 extern "C" fn _start() -> ! {
+    ipc_round_trip();
+    component_round_trip();
     let _result = unsafe { raw_syscall(1, 2, 3, 4, 5, 6) };
     loop {}
 }
+
+/// Constructs an echo server thread and calls it once through an endpoint.
+fn ipc_round_trip() {
+    construct(ConstructArgs::Endpoint, ENDPOINT_SLOT);
+    // The entry point expects a return address on the stack, as if it had
+    // been called.
+    let stack_top = unsafe { core::ptr::addr_of!(SERVER_STACK.0) as usize } + SERVER_STACK_SIZE - 8;
+    construct(
+        ConstructArgs::Thread {
+            entry: echo_server as usize,
+            stack_pointer: stack_top,
+            cap_table: BOOT_CAP_TABLE_CAP,
+            page_table: BOOT_PAGE_TABLE_CAP,
+        },
+        SERVER_SLOT,
+    );
+    // SAFETY: The server only touches its own stack.
+    unsafe { ThreadOp::Activate.syscall(SERVER_CAP) }.unwrap();
+    // SAFETY: Endpoint operations don't touch our memory.
+    let reply = unsafe {
+        EndpointOp::Call {
+            message: [1, 2, 3, 4],
+        }
+        .invoke(ENDPOINT_CAP)
+    };
+    assert_eq!(reply.unwrap(), [2, 3, 4, 5]);
+    println!("IPC round trip succeeded");
+}
+
+/// Constructs a component in our own address space and invokes it once.
+fn component_round_trip() {
+    // Same as for the server thread, the entry point is entered as if called.
+    let stack_top =
+        unsafe { core::ptr::addr_of!(COMPONENT_STACK.0) as usize } + SERVER_STACK_SIZE - 8;
+    construct(
+        ConstructArgs::Component {
+            entry: doubler_entry as usize,
+            stack_pointer: stack_top,
+            cap_table: BOOT_CAP_TABLE_CAP,
+            page_table: BOOT_PAGE_TABLE_CAP,
+        },
+        COMPONENT_SLOT,
+    );
+    // SAFETY: The component only touches its own stack.
+    let reply = unsafe {
+        ComponentOp::Invoke {
+            message: [1, 2, 3, 4],
+        }
+        .invoke(COMPONENT_CAP)
+    };
+    assert_eq!(reply.unwrap(), [2, 4, 6, 8]);
+    println!("Component round trip succeeded");
+}
+
+/// Constructs the object in the first untyped frame of the boot memory
+/// region, into `slot` of the boot capability table.
+fn construct(kind: ConstructArgs, slot: usize) {
+    let Ok(slot) = slot.try_into() else {
+        panic!("Slot {slot} is out of bounds");
+    };
+    for offset in 0.. {
+        let op = CapTableOp::<SLOTS>::Construct {
+            kind,
+            region: BOOT_MEMORY_CAP,
+            offset,
+            slot,
+        };
+        // SAFETY: Constructing an object only retypes an untyped frame.
+        match unsafe { op.syscall(BOOT_CAP_TABLE_CAP) } {
+            Ok(()) => return,
+            // The frame isn't untyped, try the next one.
+            Err(CapError::InvalidArgument) => continue,
+            Err(e) => panic!("Couldn't construct {kind:?}: {e:?}"),
+        }
+    }
+}
+
+/// Replies to every call with each word of the message incremented.
+extern "C" fn echo_server() -> ! {
+    // The first reply goes to the thread that activated us.
+    let mut reply = [0; 4];
+    loop {
+        // SAFETY: Endpoint operations don't touch our memory.
+        let message = unsafe { EndpointOp::ReplyRecv { message: reply }.invoke(ENDPOINT_CAP) };
+        reply = message.unwrap().map(|word| word + 1);
+    }
+}
+
+/// Entry point of the doubling component. The message arrives in the
+/// registers of the third to sixth arguments.
+extern "C" fn doubler_entry(_: usize, _: usize, a: usize, b: usize, c: usize, d: usize) -> ! {
+    // Calling a function needs a working stack.
+    let message = [a, b, c, d].map(double);
+    // SAFETY: Returning doesn't touch our memory. Any capability id will do.
+    let _ = unsafe { ComponentOp::Return { message }.invoke(COMPONENT_CAP) };
+    unreachable!("Returned from the component twice");
+}
+
+#[inline(never)]
+fn double(word: usize) -> usize {
+    core::hint::black_box(word) * 2
+}
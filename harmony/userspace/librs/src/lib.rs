@@ -5,7 +5,7 @@ pub use kapi;
 pub mod serial {
     use core::fmt::Write;
 
-    use kapi::raw::raw_syscall;
+    use kapi::raw::{raw_syscall, DEBUG_PRINT_CAP};
 
     #[macro_export]
     macro_rules! print {
@@ -25,7 +25,7 @@ pub mod serial {
 
     fn write(msg: &str) {
         let msg = msg.as_bytes();
-        unsafe { raw_syscall(usize::MAX, 0, msg.as_ptr() as usize, msg.len(), 0, 0) };
+        unsafe { raw_syscall(DEBUG_PRINT_CAP, 0, msg.as_ptr() as usize, msg.len(), 0, 0) };
     }
 
     struct DebugOut;
@@ -41,3 +41,23 @@ pub mod serial {
         }
     }
 }
+
+pub mod time {
+    use kapi::ops::clock::{ClockOp, BOOT_CLOCK_CAP};
+    use kapi::ops::SyscallOp as _;
+    use kapi::time::TimePage;
+
+    /// Reads the monotonic clock in nanoseconds.
+    ///
+    /// Uses the shared time page when possible and falls back to the boot
+    /// clock capability otherwise.
+    pub fn now() -> u64 {
+        // SAFETY: The kernel maps the time page in every address space.
+        let page = unsafe { TimePage::get() };
+        match page.now() {
+            Some(now) => now,
+            // SAFETY: Reading the clock has no side effects.
+            None => unsafe { ClockOp::Monotonic.syscall(BOOT_CLOCK_CAP).unwrap() },
+        }
+    }
+}